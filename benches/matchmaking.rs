@@ -0,0 +1,161 @@
+// 匹配队列的吞吐测试，cargo bench --bench matchmaking
+// 模拟几千个玩家同时开始匹配，按 33ms 一帧推进时间，统计排空队列需要的帧数和耗时
+#![allow(dead_code)]
+
+#[path = "../src/common.rs"]
+mod common;
+#[path = "../src/gamematch.rs"]
+mod gamematch;
+#[path = "../src/matchindex.rs"]
+mod matchindex;
+#[path = "../src/petable.rs"]
+mod petable;
+#[path = "../src/poemtable.rs"]
+mod poemtable;
+#[path = "../src/proto.rs"]
+mod proto;
+#[path = "../src/rating.rs"]
+mod rating;
+
+use gamematch::{
+    MatchController, MatchQueueConfig, MatchRequest, MatchSchedule, MatchScheduleConfig,
+    RematchConfig, SearchStep,
+};
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::Instant;
+
+const FRAME_TIME: i64 = 33;
+
+fn create_controller() -> MatchController {
+    let rating_system = rating::create_rating_system(
+        rating::RatingSystemType::Elo,
+        32.0,
+        &rating::Glicko2Config {
+            tau: 0.5,
+            min_deviation: 50.0,
+            deviation_per_group: 100.0,
+        },
+        &rating::RatingRules {
+            floor: Some(0),
+            provisional_games: 10,
+            provisional_k_factor: 64.0,
+        },
+    );
+    let schedule_config = MatchScheduleConfig {
+        default: MatchSchedule {
+            steps: vec![
+                SearchStep {
+                    max_wait_time: 1000,
+                    max_group: 0,
+                },
+                SearchStep {
+                    max_wait_time: 2500,
+                    max_group: 1,
+                },
+                SearchStep {
+                    max_wait_time: 3500,
+                    max_group: 2,
+                },
+                SearchStep {
+                    max_wait_time: 4000,
+                    max_group: 3,
+                },
+            ],
+            any_opponent_wait_time: 4000,
+            robot_wait_time: 4500,
+        },
+        level_brackets: Vec::new(),
+    };
+    let rematch_config = RematchConfig {
+        window: 600000,
+        max_history: 5,
+        allow_after_wait_time: 3500,
+    };
+    let queue_config = MatchQueueConfig {
+        mode: String::from("classic"),
+        area: String::from("zh"),
+        poem_table: String::from("./configs/poem.csv"),
+        robot_table: String::from("./configs/robot_info.csv"),
+        match_schedule: None,
+        rematch: None,
+        min_players: 2,
+        max_players: 2,
+        team_size: 1,
+        poem_num: 10,
+        poem_mill_time: None,
+        poem_result_wait: 2500,
+        lockstep: false,
+        rating_system: None,
+        elo_k_factor: None,
+        margin_of_victory: None,
+        tiebreak: None,
+        rating_key_name: None,
+    };
+    let mut rating_system_map = HashMap::new();
+    rating_system_map.insert(queue_config.queue_id(), rating_system);
+    return MatchController::new(
+        rating_system_map,
+        &[queue_config],
+        &schedule_config,
+        &rematch_config,
+        0,
+    );
+}
+
+fn run(player_num: usize) {
+    let mut rng = rand::thread_rng();
+    let mut match_controller = create_controller();
+    let start_timestamp: i64 = 0;
+    for i in 0..player_num {
+        let level = rng.gen_range(3..70);
+        match_controller.add_match(MatchRequest {
+            queue_id: String::from("classic_zh"),
+            endpoint_id: None,
+            player_id: format!("FakePlayerID_{}", i),
+            player_name: format!("假玩家_{}", i),
+            player_level: level,
+            player_elo_score: level as i32 * 10,
+            player_rated_games: 0,
+            player_rating_deviation: rating::DEFAULT_DEVIATION,
+            player_rating_volatility: rating::DEFAULT_VOLATILITY,
+            player_correct_rate: rng.gen_range(30.0..90.0),
+            timestamp: start_timestamp,
+        });
+    }
+
+    let mut frame_num = 0;
+    let mut matched_player_num = 0;
+    let mut robot_num = 0;
+    let mut max_frame_time = 0.0f64;
+    let begin = Instant::now();
+    while matched_player_num < player_num {
+        let curr_timestamp = start_timestamp + frame_num as i64 * FRAME_TIME;
+        let frame_begin = Instant::now();
+        let matched_vec = match_controller.update_matches(curr_timestamp);
+        max_frame_time = max_frame_time.max(frame_begin.elapsed().as_secs_f64() * 1000.0);
+        for match_group in matched_vec.iter() {
+            matched_player_num += match_group.match_requests.len();
+            robot_num += match_group.robot_num;
+        }
+        frame_num += 1;
+    }
+    let elapsed = begin.elapsed().as_secs_f64();
+
+    println!(
+        "players: {:>6}, frames: {:>4} ({} ms game time), robots: {:>4}, cpu: {:>8.2} ms, max frame: {:>6.2} ms, {:>10.0} players/s",
+        player_num,
+        frame_num,
+        frame_num as i64 * FRAME_TIME,
+        robot_num,
+        elapsed * 1000.0,
+        max_frame_time,
+        player_num as f64 / elapsed
+    );
+}
+
+fn main() {
+    for player_num in [1000, 5000, 20000] {
+        run(player_num);
+    }
+}
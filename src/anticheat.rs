@@ -0,0 +1,105 @@
+use crate::common::RedisOpt;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AntiCheatConfig {
+    pub min_reaction_time: i64,  // ms, 比这更快的答题不可能是人类
+    pub fast_reaction_time: i64, // ms, 比这更快的答对计入连续快速答对
+    pub max_fast_streak: u32,    // 允许的连续快速答对次数，超过则可疑
+    pub inhuman_reaction_score: f64,
+    pub fast_streak_score: f64,
+    pub out_of_order_score: f64,
+    pub flag_threshold: f64,    // 一局中可疑分超过该值，标记为作弊
+    pub void_flagged_elo: bool, // 被标记的对局不改变 elo 分
+}
+
+// 玩家在一局游戏中的可疑记录
+#[derive(Debug, Default)]
+pub struct CheatRecord {
+    pub fast_streak: u32,
+    pub suspicion: f64,
+}
+
+// 玩家累计的可疑分只保存在 Redis 中
+pub struct CheatChecker {
+    config: AntiCheatConfig,
+    tx: std::sync::mpsc::Sender<RedisOpt>,
+}
+
+impl CheatChecker {
+    pub fn new(config: AntiCheatConfig, tx: std::sync::mpsc::Sender<RedisOpt>) -> Self {
+        Self { config, tx }
+    }
+
+    // reaction_time: 从诗词展示到收到答题的时间
+    pub fn check_opt(
+        &mut self,
+        player_id: &str,
+        record: &mut CheatRecord,
+        reaction_time: i64,
+        is_correct: bool,
+    ) {
+        if reaction_time < self.config.min_reaction_time {
+            log::warn!(
+                "Player {} inhuman reaction time: {} ms",
+                player_id,
+                reaction_time
+            );
+            self.add_suspicion(player_id, record, self.config.inhuman_reaction_score);
+        }
+
+        if is_correct && reaction_time < self.config.fast_reaction_time {
+            record.fast_streak += 1;
+            if record.fast_streak > self.config.max_fast_streak {
+                log::warn!(
+                    "Player {} fast correct streak: {}",
+                    player_id,
+                    record.fast_streak
+                );
+                self.add_suspicion(player_id, record, self.config.fast_streak_score);
+            }
+        } else {
+            record.fast_streak = 0;
+        }
+    }
+
+    // 客户端发来了还没有轮到的题目的答案
+    pub fn check_out_of_order(
+        &mut self,
+        player_id: &str,
+        record: &mut CheatRecord,
+        opt_index: u32,
+        next_opt_index: i32,
+    ) {
+        if opt_index as i32 > next_opt_index {
+            log::warn!(
+                "Player {} OPT out of order: {}, expect: {}",
+                player_id,
+                opt_index,
+                next_opt_index
+            );
+            self.add_suspicion(player_id, record, self.config.out_of_order_score);
+        }
+    }
+
+    pub fn is_flagged(&self, record: &CheatRecord) -> bool {
+        record.suspicion >= self.config.flag_threshold
+    }
+
+    // 对局中有玩家被标记时，是否作废本局的 elo 变化
+    pub fn is_elo_voided(&self, records: &[&CheatRecord]) -> bool {
+        self.config.void_flagged_elo && records.iter().any(|record| self.is_flagged(record))
+    }
+
+    fn add_suspicion(&mut self, player_id: &str, record: &mut CheatRecord, score: f64) {
+        record.suspicion += score;
+
+        if let Ok(()) = self
+            .tx
+            .send(RedisOpt::PlayerSuspicion(player_id.to_string(), score))
+        {
+        } else {
+            log::error!("Send player {} suspicion to Redis failed!", player_id);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// 登录服务器签发的会话令牌：{player_id}.{expire_time}.{signature}
+// signature = base64url(HMAC-SHA256(secret, "{player_id}.{expire_time}"))，expire_time 为秒级时间戳
+// 匹配服务器与登录服务器共享 secret，不需要联网即可校验
+pub fn verify_token(secret: &str, player_id: &str, token: &str, curr_timestamp: i64) -> bool {
+    let mut parts = token.rsplitn(3, '.');
+    let (signature, expire_time, token_player_id) = match (parts.next(), parts.next(), parts.next())
+    {
+        (Some(signature), Some(expire_time), Some(token_player_id)) => {
+            (signature, expire_time, token_player_id)
+        }
+        _ => return false,
+    };
+
+    if token_player_id != player_id {
+        return false;
+    }
+
+    // 过期时间转成毫秒时溢出的令牌不合法
+    match expire_time
+        .parse::<i64>()
+        .ok()
+        .and_then(|expire_time| expire_time.checked_mul(1000))
+    {
+        Some(expire_timestamp) if expire_timestamp >= curr_timestamp => {}
+        Some(_) => {
+            log::warn!("Player {} token expired at {}", player_id, expire_time);
+            return false;
+        }
+        None => return false,
+    }
+
+    let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    if let Ok(mut mac) = HmacSha256::new_from_slice(secret.as_bytes()) {
+        mac.update(format!("{}.{}", token_player_id, expire_time).as_bytes());
+        return mac.verify_slice(&signature).is_ok();
+    }
+    return false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test_secret";
+    const CURR_TIMESTAMP: i64 = 1_700_000_000_000;
+
+    fn sign(player_id: &str, expire_time: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", player_id, expire_time).as_bytes());
+        let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        return format!("{}.{}.{}", player_id, expire_time, signature);
+    }
+
+    #[test]
+    fn valid_token() {
+        let token = sign("player1", "1700000060");
+        assert!(verify_token(SECRET, "player1", &token, CURR_TIMESTAMP));
+    }
+
+    #[test]
+    fn tampered_signature() {
+        let token = sign("player1", "1700000060");
+        let (payload, signature) = token.split_at(token.rfind('.').unwrap() + 1);
+        let first = if signature.starts_with('A') { "B" } else { "A" };
+        let token = format!("{}{}{}", payload, first, &signature[1..]);
+        assert!(!verify_token(SECRET, "player1", &token, CURR_TIMESTAMP));
+    }
+
+    #[test]
+    fn wrong_player_id() {
+        let token = sign("player1", "1700000060");
+        assert!(!verify_token(SECRET, "player2", &token, CURR_TIMESTAMP));
+    }
+
+    #[test]
+    fn expired_token() {
+        let token = sign("player1", "1699999999");
+        assert!(!verify_token(SECRET, "player1", &token, CURR_TIMESTAMP));
+    }
+
+    #[test]
+    fn overflowing_expire_time() {
+        let token = sign("player1", &i64::MAX.to_string());
+        assert!(!verify_token(SECRET, "player1", &token, CURR_TIMESTAMP));
+    }
+
+    #[test]
+    fn player_id_with_dot() {
+        let token = sign("player.one", "1700000060");
+        assert!(verify_token(SECRET, "player.one", &token, CURR_TIMESTAMP));
+    }
+}
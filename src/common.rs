@@ -1,7 +1,10 @@
-#[derive(Clone)]
+use crate::proto::{Encoding, GCMessage};
+use crate::rating::PlayerRating;
+use message_io::network::Endpoint;
+
 pub enum Signal {
-    Send(String, String),
-    Sync(Option<String>, Option<String>, String),
+    Send(String, GCMessage),
+    Sync(Vec<String>, GCMessage), // 同一条消息发给一局游戏中的所有玩家
 }
 
 #[derive(Clone)]
@@ -9,4 +12,40 @@ pub enum RedisOpt {
     GamePlayerData(String, u32),
     GameStatus(u32),
     ServerStatus(u32),
+    PlayerSuspicion(String, f64),     // 玩家ID，新增的可疑分
+    LoadPlayerRating(String, String), // 队列ID，玩家ID，结果通过 RedisReply 返回给游戏逻辑
+    SavePlayerRating(String, String, PlayerRating), // 队列ID，玩家ID，分数
+}
+
+// Redis线程 -> 游戏逻辑线程
+pub enum RedisReply {
+    PlayerRating(String, Option<PlayerRating>), // 玩家ID，没有记录的新玩家为 None
+    PlayerRatingFailed(String),                 // 读取失败，不能用默认分数覆盖玩家的分数
+}
+
+// 网络线程 -> 游戏逻辑线程
+pub enum ServerEvent {
+    Message(String, String), // endpoint_id, json_str
+    Disconnected(String),    // endpoint_id
+}
+
+// 网络线程中保存的客户端连接
+pub struct Connection {
+    pub endpoint: Endpoint,
+    pub encoding: Encoding,
+    pub client_version: Option<u32>, // 握手时上报的客户端版本，老版本客户端没有
+    pub client_area: Option<String>,
+    pub is_refused: bool, // 握手失败，之后的消息不再转给游戏逻辑
+}
+
+impl Connection {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            encoding: Encoding::Json,
+            client_version: None,
+            client_area: None,
+            is_refused: false,
+        }
+    }
 }
@@ -1,16 +1,49 @@
+use crate::anticheat::AntiCheatConfig;
+use crate::gamematch::{MatchQueueConfig, MatchScheduleConfig, RematchConfig, TiebreakConfig};
+use crate::rating::{Glicko2Config, MarginConfig, RatingRules, RatingSystemType};
+use crate::room::RoomConfig;
 use serde::Deserialize;
 use std::fs::File;
 use std::io::prelude::*;
 
+// 真实玩家在游戏中断线，超过等待时间后如何处理
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DisconnectPolicy {
+    Forfeit, // 直接判负，结束游戏
+    Robot,   // 交给机器人继续答题
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ServerConfig {
-    pub area: String,
+    pub area: String, // 客户端没有指定地区时使用的默认地区
     pub port: u32,
     pub poem_mill_time: i64,
     pub poem_score: u32,
+    pub disconnect_grace_time: i64, // 断线后等待重连的时间，ms
+    pub disconnect_policy: DisconnectPolicy,
+    pub min_client_version: u32, // 握手时低于该版本的客户端会被拒绝
+    pub enable_binary_encoding: bool,
+    pub trust_client_opt_result: bool, // 兼容老客户端，没有上报 opt_sign 时使用 opt_result
+    pub anti_cheat: AntiCheatConfig,
+    pub match_queues: Vec<MatchQueueConfig>, // 匹配队列，客户端按玩法和地区选择，第一个为默认队列
+    pub match_schedule: MatchScheduleConfig, // 匹配范围随等待时间放宽的规则
+    pub rematch: RematchConfig,              // 避免反复匹配到同一个对手
+    pub match_status_interval: i64,          // ms, 匹配中发送 GCMatchStatus 的间隔，0 不发送
+    pub room: RoomConfig,                    // 好友对战房间
+    pub auth_secret: Option<String>,         // 会话令牌的 HMAC 密钥，不配置则不校验玩家身份
+    pub rating_system: RatingSystemType,     // elo 或 glicko2，队列没有配置时使用
+    pub elo_k_factor: f64,
+    pub glicko2: Glicko2Config,
+    pub margin_of_victory: MarginConfig, // 按胜负差距计算实际得分，队列没有配置时使用
+    pub tiebreak: TiebreakConfig,        // 第一名平分时加赛，队列没有配置时使用
+    pub rating_rules: RatingRules,
+    pub initial_elo_score: i32, // 服务器没有记录的新玩家的初始分数
     pub match_data_key_name: String,
     pub game_num_key_name: String,
     pub clients_num_key_name: String,
+    pub suspicion_key_name: String,
+    pub rating_key_name: String, // 队列没有配置 rating_key_name 时，分数保存在该 key 加上队列ID
 }
 
 impl ServerConfig {
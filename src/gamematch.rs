@@ -1,130 +1,743 @@
+use crate::common::Signal;
+use crate::matchindex::MatchIndex;
 use crate::petable::PETable;
+use crate::proto;
+use crate::rating::{
+    self, Glicko2Config, MarginConfig, PlayerRating, RatingRules, RatingSystem, RatingSystemType,
+};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+
+// 一局游戏的玩家数量范围，多人对局最多 8 个玩家
+const MIN_PLAYERS_PER_GAME: usize = 2;
+const MAX_PLAYERS_PER_GAME: usize = 8;
+// 用最近这么多次匹配成功的等待时间估算剩余等待时间
+const RECENT_WAIT_TIME_NUM: usize = 50;
+// 清理过期的最近对手记录的间隔，ms
+const RECENT_OPPONENT_SWEEP_INTERVAL: i64 = 60000;
+
+// 比完所有题目后第一名得分相同时进行加赛，每轮一首诗，第一个答对的玩家所在的队伍获胜
+#[derive(Debug, Deserialize, Clone)]
+pub struct TiebreakConfig {
+    pub enabled: bool,
+    pub max_rounds: u32, // 超过该轮数仍没有人答对，按平局结束
+}
+
+// 避免短时间内反复匹配到同一个对手
+#[derive(Debug, Deserialize, Clone)]
+pub struct RematchConfig {
+    pub window: i64,                // ms, 在这段时间内遇到过的对手算作最近的对手
+    pub max_history: usize,         // 每个玩家最多记录的最近对手数量
+    pub allow_after_wait_time: i64, // ms, 等待超过该时间后，允许再次匹配到最近的对手
+}
+
+// 等待时间不超过 max_wait_time 时，只接受分差组不超过 max_group 的对手
+#[derive(Debug, Deserialize, Clone)]
+pub struct SearchStep {
+    pub max_wait_time: i64, // ms
+    pub max_group: u32,
+}
+
+// 匹配范围随等待时间放宽的规则
+#[derive(Debug, Deserialize, Clone)]
+pub struct MatchSchedule {
+    pub steps: Vec<SearchStep>,      // 按 max_wait_time 从小到大排列
+    pub any_opponent_wait_time: i64, // ms, 超过该时间，任何真实玩家都可以匹配
+    pub robot_wait_time: i64,        // ms, 超过该时间还没有其他玩家，使用机器人
+}
+
+impl MatchSchedule {
+    // 当前等待时间下接受的最大分差组，None 表示不限制
+    pub fn max_group(&self, waited_time: i64) -> Option<u32> {
+        if waited_time > self.any_opponent_wait_time {
+            return None;
+        }
+        if let Some(step) = self
+            .steps
+            .iter()
+            .find(|step| waited_time <= step.max_wait_time)
+            .or_else(|| self.steps.last())
+        {
+            return Some(step.max_group);
+        }
+        return Some(0);
+    }
+}
+
+// 某个等级段的玩家使用单独的匹配规则，比如新玩家更快匹配到机器人
+#[derive(Debug, Deserialize, Clone)]
+pub struct LevelBracketSchedule {
+    pub min_level: u32,
+    pub max_level: u32,
+    #[serde(flatten)]
+    pub schedule: MatchSchedule,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MatchScheduleConfig {
+    #[serde(flatten)]
+    pub default: MatchSchedule,
+    #[serde(default)]
+    pub level_brackets: Vec<LevelBracketSchedule>,
+}
+
+impl MatchScheduleConfig {
+    pub fn schedule_for_level(&self, level: u32) -> &MatchSchedule {
+        if let Some(bracket) = self
+            .level_brackets
+            .iter()
+            .find(|bracket| level >= bracket.min_level && level <= bracket.max_level)
+        {
+            return &bracket.schedule;
+        }
+        return &self.default;
+    }
+}
+
+// 一个匹配队列，按玩法和地区区分，每个队列使用自己的诗词表、机器人表和匹配规则
+#[derive(Debug, Deserialize, Clone)]
+pub struct MatchQueueConfig {
+    pub mode: String,        // 玩法
+    pub area: String,        // 地区，zh、en
+    pub poem_table: String,  // 诗词表 csv 路径
+    pub robot_table: String, // 机器人表 csv 路径
+    #[serde(default)]
+    pub match_schedule: Option<MatchScheduleConfig>, // 不配置则使用全局的匹配规则
+    #[serde(default)]
+    pub rematch: Option<RematchConfig>,
+    #[serde(default = "default_players")]
+    pub min_players: usize, // 一局最少的玩家数，不够时用机器人补齐
+    #[serde(default = "default_players")]
+    pub max_players: usize, // 一局最多的玩家数，1v1 为 2，多人对局 3 - 8
+    #[serde(default = "default_team_size")]
+    pub team_size: usize, // 每队的玩家数，1 为各自为战，大于 1 时按 max_players 凑齐整队才开始
+    #[serde(default = "default_poem_num")]
+    pub poem_num: u32, // 每局的题目数
+    #[serde(default)]
+    pub poem_mill_time: Option<i64>, // ms, 每首诗的答题时间，不配置时使用服务器的 poem_mill_time
+    #[serde(default = "default_poem_result_wait")]
+    pub poem_result_wait: i64, // ms, 答完一首诗后显示结果的时间，比客户端多1s
+    #[serde(default)]
+    pub lockstep: bool, // 所有玩家同步回答同一首诗，都答完或超时后才开始下一首
+    #[serde(default)]
+    pub rating_system: Option<RatingSystemType>, // 不配置则使用服务器的分数系统
+    #[serde(default)]
+    pub elo_k_factor: Option<f64>,
+    #[serde(default)]
+    pub margin_of_victory: Option<MarginConfig>,
+    #[serde(default)]
+    pub tiebreak: Option<TiebreakConfig>,
+    #[serde(default)]
+    pub rating_key_name: Option<String>, // 保存分数的 Redis key，不配置则为服务器的 rating_key_name 加上队列ID
+}
+
+fn default_players() -> usize {
+    MIN_PLAYERS_PER_GAME
+}
+
+fn default_team_size() -> usize {
+    1
+}
+
+fn default_poem_num() -> u32 {
+    10
+}
+
+fn default_poem_result_wait() -> i64 {
+    2500
+}
+
+// 按分数分队，分数从高到低蛇形轮流分到每一队，让每队的分数尽量接近
+// 返回每个玩家所在的队伍，team_size 不大于 1 时每个玩家单独一队
+pub fn assign_teams(elo_scores: &[i32], team_size: usize) -> Vec<usize> {
+    if team_size <= 1 {
+        return (0..elo_scores.len()).collect();
+    }
+    let team_num = elo_scores.len().div_ceil(team_size).max(1);
+    let mut index_vec: Vec<usize> = (0..elo_scores.len()).collect();
+    index_vec.sort_by(|a, b| elo_scores[*b].cmp(&elo_scores[*a]));
+
+    let mut team_vec: Vec<usize> = vec![0; elo_scores.len()];
+    for (order, index) in index_vec.iter().enumerate() {
+        let round = order / team_num;
+        let position = order % team_num;
+        // 偶数轮正序，奇数轮倒序
+        team_vec[*index] = match round % 2 {
+            0 => position,
+            _ => team_num - 1 - position,
+        };
+    }
+    return team_vec;
+}
+
+impl MatchQueueConfig {
+    pub fn queue_id(&self) -> String {
+        format!("{}_{}", self.mode, self.area)
+    }
+
+    // 每个队列的分数单独保存，不同玩法的分数互不影响
+    pub fn rating_key_name(&self, default_key_name: &str) -> String {
+        match self.rating_key_name {
+            Some(ref key_name) => key_name.clone(),
+            None => format!("{}_{}", default_key_name, self.queue_id()),
+        }
+    }
+
+    // 队列没有配置的分数系统和 K 值使用服务器的
+    pub fn create_rating_system(
+        &self,
+        default_rating_system: RatingSystemType,
+        default_k_factor: f64,
+        glicko2_config: &Glicko2Config,
+        rules: &RatingRules,
+    ) -> Box<dyn RatingSystem> {
+        return rating::create_rating_system(
+            self.rating_system.unwrap_or(default_rating_system),
+            self.elo_k_factor.unwrap_or(default_k_factor),
+            glicko2_config,
+            rules,
+        );
+    }
+}
+
+// 匹配成功的一组玩家，等待最久的玩家在最前面，robot_num 为需要补齐的机器人数量
+#[derive(Debug)]
+pub struct MatchGroup {
+    pub match_requests: Vec<MatchRequest>,
+    pub robot_num: usize,
+    pub team_size: usize, // 补齐机器人后再按分数分队
+}
 
 #[derive(Debug)]
 pub struct MatchRequest {
+    pub queue_id: String, // 所在的匹配队列，也决定了游戏使用的诗词表和机器人表
     pub endpoint_id: Option<String>,
     pub player_id: String,
     pub player_name: String,
     pub player_level: u32,
-    pub player_elo_score: u32,
+    pub player_elo_score: i32,
+    pub player_rated_games: u32,
+    pub player_rating_deviation: f64,
+    pub player_rating_volatility: f64,
     pub player_correct_rate: f64,
     pub timestamp: i64,
 }
 
+impl MatchRequest {
+    // 使用服务器保存的分数，客户端上报的不可信
+    pub fn apply_rating(&mut self, rating: PlayerRating) {
+        self.player_elo_score = rating.elo_score;
+        self.player_level = rating.level;
+        self.player_rated_games = rating.rated_games;
+        self.player_rating_deviation = rating.deviation;
+        self.player_rating_volatility = rating.volatility;
+    }
+}
+
+// 单个匹配队列的状态
+struct MatchQueue {
+    mode: String,
+    area: String,
+    min_players: usize,
+    max_players: usize,
+    team_size: usize,
+    rating_system: Box<dyn RatingSystem>,
+    match_index: MatchIndex,
+    schedule_config: MatchScheduleConfig,
+    recent_wait_times: VecDeque<i64>, // 最近匹配成功的玩家等待的时间
+    rematch_config: RematchConfig,
+    // 玩家ID - 最近的对手ID和匹配成功的时间戳
+    recent_opponent_map: HashMap<String, VecDeque<(String, i64)>>,
+    last_sweep_timestamp: i64,
+}
+
 pub struct MatchController {
-    match_vec: Vec<MatchRequest>,
+    // 队列ID - 匹配队列，queue_id_vec 保持配置中的顺序
+    queue_map: HashMap<String, MatchQueue>,
+    queue_id_vec: Vec<String>,
+    // 玩家ID - 等待从 Redis 读取分数的匹配请求
+    pending_map: HashMap<String, MatchRequest>,
     // last_update_timestamp: i64,
     pe_table: PETable,
+    status_interval: i64, // ms, 给匹配中的玩家发送 GCMatchStatus 的间隔
+    last_status_timestamp: i64,
 }
 
 impl MatchController {
-    pub fn new() -> Self {
+    // rating_system_map: 队列ID - 该队列的分数系统
+    pub fn new(
+        mut rating_system_map: HashMap<String, Box<dyn RatingSystem>>,
+        queue_config_vec: &[MatchQueueConfig],
+        default_schedule_config: &MatchScheduleConfig,
+        default_rematch_config: &RematchConfig,
+        status_interval: i64,
+    ) -> Self {
+        let mut queue_map: HashMap<String, MatchQueue> = HashMap::new();
+        let mut queue_id_vec: Vec<String> = Vec::new();
+        for queue_config in queue_config_vec.iter() {
+            let queue_id = queue_config.queue_id();
+            let rating_system = match rating_system_map.remove(&queue_id) {
+                Some(rating_system) => rating_system,
+                None => {
+                    log::error!("Match queue {} has no rating system, skipped", queue_id);
+                    continue;
+                }
+            };
+            let team_size = queue_config
+                .team_size
+                .clamp(1, MAX_PLAYERS_PER_GAME / MIN_PLAYERS_PER_GAME);
+            let (min_players, max_players) = if team_size > 1 {
+                // 组队时至少两队，人数必须是整队
+                let team_num = (queue_config.max_players / team_size)
+                    .clamp(MIN_PLAYERS_PER_GAME, MAX_PLAYERS_PER_GAME / team_size);
+                (team_num * team_size, team_num * team_size)
+            } else {
+                let max_players = queue_config
+                    .max_players
+                    .clamp(MIN_PLAYERS_PER_GAME, MAX_PLAYERS_PER_GAME);
+                (
+                    queue_config
+                        .min_players
+                        .clamp(MIN_PLAYERS_PER_GAME, max_players),
+                    max_players,
+                )
+            };
+            log::info!(
+                "Match queue {} added, players: {} - {}, team size: {}",
+                queue_id,
+                min_players,
+                max_players,
+                team_size
+            );
+            queue_id_vec.push(queue_id.clone());
+            queue_map.insert(
+                queue_id,
+                MatchQueue {
+                    mode: queue_config.mode.clone(),
+                    area: queue_config.area.clone(),
+                    min_players,
+                    max_players,
+                    team_size,
+                    rating_system,
+                    match_index: MatchIndex::new(),
+                    schedule_config: queue_config
+                        .match_schedule
+                        .clone()
+                        .unwrap_or_else(|| default_schedule_config.clone()),
+                    recent_wait_times: VecDeque::new(),
+                    rematch_config: queue_config
+                        .rematch
+                        .clone()
+                        .unwrap_or_else(|| default_rematch_config.clone()),
+                    recent_opponent_map: HashMap::new(),
+                    last_sweep_timestamp: 0,
+                },
+            );
+        }
+
         Self {
+            queue_map,
+            queue_id_vec,
+            status_interval,
+            last_status_timestamp: 0,
             // last_update_timestamp: -1,
             pe_table: PETable::new(),
-            match_vec: Vec::new(),
+            pending_map: HashMap::new(),
+        }
+    }
+
+    // 按玩法和地区查找匹配队列，为空的条件不限制，返回配置中第一个符合的队列
+    pub fn find_queue(&self, mode: &str, area: &str) -> Option<String> {
+        return self
+            .queue_id_vec
+            .iter()
+            .find(|queue_id| match self.queue_map.get(*queue_id) {
+                Some(queue) => {
+                    (mode.is_empty() || queue.mode == mode)
+                        && (area.is_empty() || queue.area == area)
+                }
+                None => false,
+            })
+            .cloned();
+    }
+
+    // 先从 Redis 读取玩家的分数，读取完成后才加入匹配队列
+    pub fn add_pending_match(&mut self, match_request: MatchRequest) {
+        log::info!("New Match pending rating: {:?}", match_request);
+        self.pending_map
+            .insert(match_request.player_id.clone(), match_request);
+    }
+
+    // 分数读取完成，使用服务器保存的分数加入匹配队列，没有记录的玩家使用默认分数
+    pub fn on_rating_loaded(&mut self, player_id: &str, some_rating: Option<PlayerRating>) {
+        if let Some(mut match_request) = self.pending_map.remove(player_id) {
+            if let Some(rating) = some_rating {
+                match_request.apply_rating(rating);
+            }
+            self.add_match(match_request);
         }
     }
 
     pub fn add_match(&mut self, match_request: MatchRequest) {
         log::info!("New Match added: {:?}", match_request);
-        self.match_vec.push(match_request);
+        // 同一个玩家只能在一个队列中匹配
+        if let Some(old_request) = self.remove_queued(&match_request.player_id) {
+            log::warn!("Old match replaced: {:?}", old_request);
+        }
+        if let Some(queue) = self.queue_map.get_mut(&match_request.queue_id) {
+            queue.match_index.insert(match_request);
+        } else {
+            log::error!("Unknown match queue: {:?}", match_request);
+        }
     }
 
-    pub fn update_matches(
+    fn remove_queued(&mut self, player_id: &str) -> Option<MatchRequest> {
+        for queue in self.queue_map.values_mut() {
+            if let Some(match_request) = queue.match_index.remove(player_id) {
+                return Some(match_request);
+            }
+        }
+        return None;
+    }
+
+    // 玩家主动退出匹配
+    pub fn cancel_match(&mut self, player_id: &str) -> Option<MatchRequest> {
+        if let Some(match_request) = self.pending_map.remove(player_id) {
+            log::info!("Pending match canceled: {:?}", match_request);
+            return Some(match_request);
+        }
+
+        if let Some(match_request) = self.remove_queued(player_id) {
+            log::info!("Match canceled: {:?}", match_request);
+            return Some(match_request);
+        }
+        return None;
+    }
+
+    // 连接断开时，该连接上还没有匹配成功的请求也一并取消
+    pub fn cancel_match_by_endpoint(&mut self, endpoint_id: &str) -> Option<MatchRequest> {
+        let some_player_id = self
+            .pending_map
+            .values()
+            .find(|match_req| match_req.endpoint_id.as_deref() == Some(endpoint_id))
+            .or_else(|| {
+                self.queue_map
+                    .values()
+                    .find_map(|queue| queue.match_index.find_by_endpoint(endpoint_id))
+            })
+            .map(|match_req| match_req.player_id.clone());
+
+        if let Some(player_id) = some_player_id {
+            log::info!("Match canceled by disconnect: {}", player_id);
+            return self.cancel_match(&player_id);
+        }
+        return None;
+    }
+
+    // 每个队列各自匹配，一帧内返回所有匹配成功的玩家组
+    pub fn update_matches(&mut self, curr_timestamp: i64) -> Vec<MatchGroup> {
+        // self.last_update_timestamp = curr_timestamp;
+        let mut matched_vec: Vec<MatchGroup> = Vec::new();
+        for queue_id in self.queue_id_vec.iter() {
+            if let Some(queue) = self.queue_map.get_mut(queue_id) {
+                queue.update_matches(curr_timestamp, &self.pe_table, &mut matched_vec);
+            }
+        }
+        return matched_vec;
+    }
+
+    // 定时告诉匹配中的玩家当前的匹配进度
+    pub fn update_match_status(&mut self, curr_timestamp: i64) -> Vec<Signal> {
+        let mut signal_vec: Vec<Signal> = Vec::new();
+        if self.status_interval <= 0
+            || curr_timestamp - self.last_status_timestamp < self.status_interval
+        {
+            return signal_vec;
+        }
+        self.last_status_timestamp = curr_timestamp;
+
+        for queue in self.queue_map.values() {
+            queue.update_match_status(curr_timestamp, &mut signal_vec);
+        }
+        return signal_vec;
+    }
+}
+
+impl MatchQueue {
+    // 等待最久的玩家先找对手，匹配成功的玩家放入 matched_vec
+    fn update_matches(
         &mut self,
         curr_timestamp: i64,
-    ) -> Option<(Option<MatchRequest>, Option<MatchRequest>)> {
-        // self.last_update_timestamp = curr_timestamp;
-        let len = self.match_vec.len();
-        // log::info!("Update matches!, {}, len: {}", curr_timestamp, len);
-        if len == 0 {
-            return None;
+        pe_table: &PETable,
+        matched_vec: &mut Vec<MatchGroup>,
+    ) {
+        // log::info!("Update matches!, {}, len: {}", curr_timestamp, self.match_index.len());
+        if self.match_index.is_empty() {
+            return;
         }
+        self.sweep_recent_opponents(curr_timestamp);
 
-        for imain in 0..len {
-            let mut min_index = -1;
-            let mut min_score_diff = 0;
-            if let Some(match_req) = self.match_vec.get(imain) {
-                // 找到 elo_score 相差最小的另一个玩家
-                for icheck in (imain + 1)..len {
-                    if let Some(check_req) = self.match_vec.get(icheck) {
-                        let player1_elo_score = match_req.player_elo_score;
-                        let player2_elo_score = check_req.player_elo_score;
-                        let diff = if player1_elo_score > player2_elo_score {
-                            player1_elo_score - player2_elo_score
-                        } else {
-                            player2_elo_score - player1_elo_score
-                        };
-
-                        if min_index < 0 || diff < min_score_diff {
-                            min_index = icheck as i32;
-                            min_score_diff = diff;
-                        }
-                    }
-                }
+        // 匹配成功会从索引中移除玩家，记下最后匹配成功的玩家，从它之后继续按等待时间查找
+        let mut some_cursor: Option<(i64, String)> = None;
+        loop {
+            let some_group = self
+                .match_index
+                .iter_by_wait(some_cursor.as_ref())
+                .find_map(|match_req| {
+                    let (opponent_id_vec, robot_num) =
+                        self.find_group(match_req, curr_timestamp, pe_table)?;
+                    let mut group_id_vec = vec![match_req.player_id.clone()];
+                    group_id_vec.extend(opponent_id_vec.into_iter().map(String::from));
+                    let cursor = (match_req.timestamp, match_req.player_id.clone());
+                    Some((cursor, group_id_vec, robot_num))
+                });
+            let (cursor, group_id_vec, robot_num) = match some_group {
+                Some(group) => group,
+                None => break,
+            };
+            some_cursor = Some(cursor);
 
-                // 先判断有没有匹配超时
-                let waited_time = curr_timestamp - match_req.timestamp;
-                let mut use_robot = false;
-                let mut matched = false;
-
-                // log::info!("waited_time: {}", waited_time);
-
-                // 找到一个潜在的对手后，根据当前玩家等待的时间判断，对手是否满足要求
-                if min_index > 0 {
-                    if let Some(check_req) = self.match_vec.get(min_index as usize) {
-                        let (_ea, _eb, group) = self
-                            .pe_table
-                            .get_ea_eb(match_req.player_elo_score, check_req.player_elo_score);
-
-                        if waited_time <= 1000 && group <= 0 {
-                            log::info!("MATCHED!, waited_time < 1000, group <= 0");
-                            // 完美匹配
-                            matched = true;
-                        } else if waited_time <= 2500 && group <= 1 {
-                            log::info!("MATCHED!, waited_time < 2500, group <= 1");
-                            // 匹配成功
-                            matched = true;
-                        } else if waited_time <= 3500 && group <= 2 {
-                            log::info!("MATCHED!, waited_time < 3500, group <= 2");
-                            // 匹配成功
-                            matched = true;
-                        } else if waited_time <= 4000 && group <= 3 {
-                            log::info!("MATCHED!, waited_time < 4500 group <= 3");
-                            matched = true;
-                        } else {
-                            // if group <= 4 {
-                            if waited_time > 4000 {
-                                log::info!("MATCHED! no condition, just real player!");
-                                matched = true;
-                            }
-                            // }
-                        }
-                    }
-                } else {
-                    // 没有找到合适的潜在对手，也就是没有玩家了，判断一下时间，超时则使用机器人
-                    if waited_time > 4500 {
-                        log::info!("Match Failed!, USE ROBOT!");
-                        matched = true;
-                        use_robot = true;
+            let match_request_vec: Vec<MatchRequest> = group_id_vec
+                .iter()
+                .filter_map(|group_player_id| self.match_index.remove(group_player_id))
+                .collect();
+            for match_request in match_request_vec.iter() {
+                self.add_recent_wait_time(curr_timestamp - match_request.timestamp);
+            }
+            for group_player_id in group_id_vec.iter() {
+                for opponent_id in group_id_vec.iter() {
+                    if group_player_id != opponent_id {
+                        self.add_recent_opponent(group_player_id, opponent_id, curr_timestamp);
                     }
                 }
+            }
+            matched_vec.push(MatchGroup {
+                match_requests: match_request_vec,
+                robot_num,
+                team_size: self.team_size,
+            });
+        }
+    }
 
-                if matched {
-                    if use_robot {
-                        let player1_match_req = self.match_vec.remove(imain);
-                        return Some((Some(player1_match_req), None));
-                    } else {
-                        // 如果 remove 失败，则会 Panic。因为是从前往后检查的，所以要先移除后面的
-                        let player2_match_req = self.match_vec.remove(min_index as usize);
-                        let player1_match_req = self.match_vec.remove(imain);
-                        return Some((Some(player1_match_req), Some(player2_match_req)));
-                    }
+    // 按等待时间判断该玩家能不能开始游戏，可以则返回对手ID和需要补齐的机器人数量
+    fn find_group<'a>(
+        &'a self,
+        match_req: &'a MatchRequest,
+        curr_timestamp: i64,
+        pe_table: &PETable,
+    ) -> Option<(Vec<&'a str>, usize)> {
+        let player_id = &match_req.player_id;
+        // 先判断有没有匹配超时
+        let waited_time = curr_timestamp - match_req.timestamp;
+        let schedule = self
+            .schedule_config
+            .schedule_for_level(match_req.player_level);
+
+        // log::info!("waited_time: {}", waited_time);
+
+        // 按 elo_score 相差从小到大找其他玩家，根据当前玩家等待的时间判断，对手是否满足要求
+        // 最近遇到过的对手跳过，等待足够久之后才允许再次匹配
+        let allow_rematch = waited_time > self.rematch_config.allow_after_wait_time;
+        let some_max_group = schedule.max_group(waited_time);
+        // 分数不确定的玩家（新玩家）放宽分差要求
+        let group_bonus = self
+            .rating_system
+            .search_group_bonus(match_req.player_rating_deviation);
+        let opponent_id_vec: Vec<&str> = self
+            .match_index
+            .neighbors(player_id)
+            .filter(|check_req| {
+                allow_rematch
+                    || !self.is_recent_opponent(player_id, &check_req.player_id, curr_timestamp)
+            })
+            .take_while(|check_req| match some_max_group {
+                Some(max_group) => {
+                    let (_ea, _eb, group) =
+                        pe_table.get_ea_eb(match_req.player_elo_score, check_req.player_elo_score);
+                    group.saturating_sub(group_bonus) <= max_group
                 }
-            }
+                None => true,
+            })
+            .take(self.max_players - 1)
+            .map(|check_req| check_req.player_id.as_str())
+            .collect();
+
+        // 人数够了直接开始，超过不限分差的时间后达到最少人数也可以开始，超时则用机器人补齐最少人数
+        let player_num = opponent_id_vec.len() + 1;
+        let robot_num = if player_num >= self.max_players {
+            log::info!(
+                "MATCHED!, waited_time: {}, max_group: {:?}, players: {}",
+                waited_time,
+                some_max_group,
+                player_num
+            );
+            0
+        } else if player_num >= self.min_players && waited_time > schedule.any_opponent_wait_time {
+            log::info!("MATCHED! no condition, players: {}", player_num);
+            0
+        } else if waited_time > schedule.robot_wait_time {
+            log::info!("Match Failed!, USE ROBOT!, players: {}", player_num);
+            self.min_players.saturating_sub(player_num)
+        } else {
+            return None;
+        };
+        return Some((opponent_id_vec, robot_num));
+    }
+
+    fn is_recent_opponent(&self, player_id: &str, opponent_id: &str, curr_timestamp: i64) -> bool {
+        if let Some(opponent_deque) = self.recent_opponent_map.get(player_id) {
+            return opponent_deque
+                .iter()
+                .any(|(recent_opponent_id, timestamp)| {
+                    recent_opponent_id == opponent_id
+                        && curr_timestamp - timestamp <= self.rematch_config.window
+                });
         }
+        return false;
+    }
 
-        return None;
+    fn add_recent_opponent(&mut self, player_id: &str, opponent_id: &str, curr_timestamp: i64) {
+        if self.rematch_config.max_history == 0 {
+            return;
+        }
+        let opponent_deque = self
+            .recent_opponent_map
+            .entry(player_id.to_string())
+            .or_default();
+        opponent_deque.push_back((opponent_id.to_string(), curr_timestamp));
+        while opponent_deque.len() > self.rematch_config.max_history {
+            opponent_deque.pop_front();
+        }
+    }
+
+    // 定时清掉已经过了窗口期的记录，避免不再匹配的玩家一直占用内存
+    fn sweep_recent_opponents(&mut self, curr_timestamp: i64) {
+        if curr_timestamp - self.last_sweep_timestamp < RECENT_OPPONENT_SWEEP_INTERVAL {
+            return;
+        }
+        self.last_sweep_timestamp = curr_timestamp;
+
+        let window = self.rematch_config.window;
+        self.recent_opponent_map.retain(|_, opponent_deque| {
+            opponent_deque.retain(|(_, timestamp)| curr_timestamp - *timestamp <= window);
+            !opponent_deque.is_empty()
+        });
+    }
+
+    fn add_recent_wait_time(&mut self, waited_time: i64) {
+        self.recent_wait_times.push_back(waited_time);
+        if self.recent_wait_times.len() > RECENT_WAIT_TIME_NUM {
+            self.recent_wait_times.pop_front();
+        }
+    }
+
+    // 按最近匹配成功的平均等待时间估算，不会超过使用机器人的时间
+    fn estimate_remaining_time(&self, waited_time: i64, schedule: &MatchSchedule) -> i64 {
+        let robot_remaining_time = (schedule.robot_wait_time - waited_time).max(0);
+        if self.recent_wait_times.is_empty() {
+            return robot_remaining_time;
+        }
+        let average_wait_time =
+            self.recent_wait_times.iter().sum::<i64>() / self.recent_wait_times.len() as i64;
+        return (average_wait_time - waited_time)
+            .max(0)
+            .min(robot_remaining_time);
+    }
+
+    fn update_match_status(&self, curr_timestamp: i64, signal_vec: &mut Vec<Signal>) {
+        let queue_size = self.match_index.len() as u32;
+        for match_req in self.match_index.iter() {
+            let endpoint_id = match match_req.endpoint_id {
+                Some(ref endpoint_id) => endpoint_id.clone(),
+                None => continue,
+            };
+            let waited_time = curr_timestamp - match_req.timestamp;
+            let schedule = self
+                .schedule_config
+                .schedule_for_level(match_req.player_level);
+            let gc_match_status = proto::GCMatchStatus {
+                waited_time,
+                search_group: match schedule.max_group(waited_time) {
+                    Some(max_group) => max_group as i32,
+                    None => -1,
+                },
+                queue_size,
+                estimated_time: self.estimate_remaining_time(waited_time, schedule),
+            };
+            let proto_json_str =
+                proto::ProtoData::gc_to_message(proto::PROTO_GCMATCHSTATUS, gc_match_status);
+            signal_vec.push(Signal::Send(endpoint_id, proto_json_str));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn team_sums(elo_scores: &[i32], team_vec: &[usize]) -> Vec<i32> {
+        let team_num = team_vec.iter().max().map_or(0, |team| team + 1);
+        let mut sum_vec = vec![0; team_num];
+        for (elo_score, team) in elo_scores.iter().zip(team_vec) {
+            sum_vec[*team] += elo_score;
+        }
+        return sum_vec;
+    }
+
+    fn assert_balanced(elo_scores: &[i32], team_size: usize, max_diff: i32) {
+        let team_vec = assign_teams(elo_scores, team_size);
+        assert_eq!(team_vec.len(), elo_scores.len());
+        let team_num = elo_scores.len().div_ceil(team_size);
+        for team in 0..team_num {
+            let member_num = team_vec.iter().filter(|t| **t == team).count();
+            assert_eq!(member_num, team_size, "team {} in {:?}", team, team_vec);
+        }
+        let sum_vec = team_sums(elo_scores, &team_vec);
+        let max_sum = sum_vec.iter().max().unwrap();
+        let min_sum = sum_vec.iter().min().unwrap();
+        assert!(
+            max_sum - min_sum <= max_diff,
+            "{:?} -> {:?}",
+            elo_scores,
+            sum_vec
+        );
+    }
+
+    #[test]
+    fn teams_balanced_for_sorted_elo() {
+        assert_balanced(&[1600, 1500, 1400, 1300], 2, 0);
+        assert_balanced(&[1300, 1400, 1500, 1600], 2, 0);
+        assert_balanced(&[1800, 1700, 1600, 1500, 1400, 1300], 2, 0);
+        assert_balanced(&[1000, 1100, 1200, 1300, 1400, 1500, 1600, 1700], 4, 0);
+    }
+
+    #[test]
+    fn teams_balanced_for_unsorted_elo() {
+        assert_balanced(&[1400, 1600, 1300, 1500], 2, 0);
+        assert_balanced(&[1500, 1300, 1800, 1400, 1700, 1600], 2, 0);
+        assert_balanced(&[1200, 1700, 1000, 1500, 1600, 1100, 1400, 1300], 4, 0);
+        // 分数分散时总分不一定相等，只检查差距在一个玩家的分差以内
+        assert_balanced(&[1520, 980, 1340, 1710, 1190, 1455], 3, 300);
+    }
+
+    #[test]
+    fn team_sums_ignore_input_order() {
+        let sorted_scores = [1710, 1520, 1455, 1340, 1190, 980];
+        let unsorted_scores = [1340, 980, 1710, 1190, 1455, 1520];
+        for team_size in 2..=3 {
+            let mut sorted_sums =
+                team_sums(&sorted_scores, &assign_teams(&sorted_scores, team_size));
+            let mut unsorted_sums =
+                team_sums(&unsorted_scores, &assign_teams(&unsorted_scores, team_size));
+            sorted_sums.sort();
+            unsorted_sums.sort();
+            assert_eq!(sorted_sums, unsorted_sums);
+        }
+    }
+
+    #[test]
+    fn single_player_teams() {
+        assert_eq!(assign_teams(&[1500, 1600, 1400], 1), vec![0, 1, 2]);
+        assert_eq!(assign_teams(&[1500, 1600], 0), vec![0, 1]);
     }
 }
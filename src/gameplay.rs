@@ -1,13 +1,49 @@
+use crate::anticheat::{AntiCheatConfig, CheatChecker, CheatRecord};
 use crate::common::{RedisOpt, Signal};
-use crate::gamematch::MatchRequest;
-use crate::petable::PETable;
-use crate::poemtable::PoemTable;
+use crate::config::DisconnectPolicy;
+use crate::gamematch::{self, MatchQueueConfig, MatchRequest, TiebreakConfig};
+use crate::poemtable::{PoemLineRecord, PoemTable};
 use crate::proto;
+use crate::rating::{self, MarginConfig, PlayerRating, RatingSystem};
 use crate::robot::{Robot, RobotController};
 use std::collections::HashMap;
 
-const MATCH_POEM_NUM: u32 = 10;
-const POEM_RESULT_WAIT: i64 = 2500; //ms, 比客户端多1s
+// GCEndGame.end_type
+pub const END_TYPE_NORMAL: i32 = 0;
+pub const END_TYPE_PLAYER1_FORFEIT: i32 = 1; // 玩家1断线判负
+pub const END_TYPE_PLAYER2_FORFEIT: i32 = 2; // 玩家2断线判负
+pub const END_TYPE_BOTH_FORFEIT: i32 = 3; // 所有玩家都断线了
+pub const END_TYPE_PLAYER_FORFEIT: i32 = 4; // 多人对局中有玩家断线判负
+
+// GCResumeGame.code
+pub const RESUME_GAME_NOT_FOUND: i32 = -1; // 游戏不存在或已结束
+pub const RESUME_GAME_FORFEIT: i32 = -2; // 玩家已经断线判负，不能回到游戏
+
+const CLIENT_RESULT_SLACK: i64 = 1000; // ms, 服务器的结果等待时间比客户端多1s
+
+// 每局游戏的题目数和答题时间，由所在队列的玩法决定
+#[derive(Debug, Clone, Copy)]
+struct GameRules {
+    poem_num: u32,
+    poem_mill_time: i64,   // ms, 每首诗的答题时间
+    poem_result_wait: i64, // ms, 答完一首诗后显示结果的时间，比客户端多1s
+    lockstep: bool,        // 所有玩家同步回答同一首诗
+}
+
+impl GameRules {
+    // 答完一首诗后，客户端显示完结果开始展示下一首诗的时间
+    fn next_opt_start(&self, curr_timestamp: i64) -> i64 {
+        curr_timestamp + (self.poem_result_wait - CLIENT_RESULT_SLACK).max(0)
+    }
+}
+
+// 处理玩家答题时用到的玩法规则、服务器配置和作弊检查
+struct OptContext<'a> {
+    rules: &'a GameRules,
+    poem_score: u32,
+    trust_client_opt_result: bool, // 兼容老客户端，没有上报 opt_sign 时使用 opt_result
+    cheat_checker: &'a mut CheatChecker,
+}
 
 #[derive(Debug)]
 pub struct Player {
@@ -15,63 +51,158 @@ pub struct Player {
     player_id: String,
     player_name: String,
     player_level: u32,
-    player_elo_score: u32,
+    player_elo_score: i32,
+    player_rated_games: u32,
+    player_rating_deviation: f64,
+    player_rating_volatility: f64,
     player_correct_rate: f64,
+    team_id: usize,            // 所在的队伍，不组队时每个玩家单独一队
     game_start_timestamp: i64, // 游戏开始时间戳
     next_opt_index: i32,
+    opt_end_index: i32, // 要答的题目数，参加加赛时每轮加一
     next_opt_timeout_timestamp: i64,
-    opt_bitmap: u32, // 操作位数据, 0 正确，1 错误
+    opt_start_timestamp: i64, // 当前这首诗在客户端开始展示的时间，用于计算反应时间
+    opt_results: Vec<u32>,    // 按题目顺序每道题的结果, 0 正确，1 错误
+    opt_timestamps: Vec<i64>, // 按题目顺序每道题的作答时间，加赛时比较谁先答对
     is_dirty: bool,
     robot: Option<Robot>,
-    game_score: u32, // 本局游戏得分，根据操作时间来的
+    game_score: u32,                   // 本局游戏得分，根据操作时间来的
+    disconnect_timestamp: Option<i64>, // 断线的时间戳，重连后清除
+    is_robot_takeover: bool,           // 断线后由机器人接管
+    is_forfeit: bool,                  // 断线超时被判负
+    cheat_record: CheatRecord,         // 本局的作弊可疑记录
 }
 
 impl Player {
+    pub fn player_id(&self) -> &str {
+        &self.player_id
+    }
+
+    // 是否是机器人玩家，被机器人接管的真实玩家不算
+    pub fn is_robot(&self) -> bool {
+        self.robot.is_some() && !self.is_robot_takeover
+    }
+
     fn is_all_opt_end(&self) -> bool {
-        self.next_opt_index >= MATCH_POEM_NUM as i32
+        self.next_opt_index >= self.opt_end_index
+    }
+
+    // 答完所有题目或者被判负
+    fn is_finished(&self) -> bool {
+        self.is_all_opt_end() || self.is_forfeit
+    }
+
+    // 已经作答的题目中答对的数量，不算加赛题目
+    fn correct_count(&self, poem_num: u32) -> u32 {
+        self.opt_results
+            .iter()
+            .take(poem_num as usize)
+            .filter(|opt_result| **opt_result == 0)
+            .count() as u32
+    }
+
+    fn is_opt_correct(&self, opt_index: i32) -> bool {
+        opt_index >= 0 && self.opt_results.get(opt_index as usize) == Some(&0)
+    }
+
+    // 兼容老客户端的操作位数据，只有前 32 题
+    fn opt_bitmap(&self) -> u32 {
+        self.opt_results
+            .iter()
+            .take(u32::BITS as usize)
+            .enumerate()
+            .fold(0, |opt_bitmap, (index, opt_result)| {
+                opt_bitmap | (opt_result << index)
+            })
     }
 
     fn on_opt(
         &mut self,
         opt: proto::CGMatchGameOpt,
         curr_timestamp: i64,
-        poem_mill_time: i64,
-        poem_score: u32,
-    ) {
-        if self.next_opt_index < MATCH_POEM_NUM as i32 {
+        poem_line: Option<&PoemLineRecord>,
+        context: &mut OptContext,
+    ) -> Result<(), proto::ErrorCode> {
+        let rules = context.rules;
+        // 断线判负后的答题不再计入
+        if self.is_forfeit {
+            log::warn!("Player {} OPT after forfeit!", self.player_id);
+            return Err(proto::ErrorCode::Forfeited);
+        }
+        if self.next_opt_index < self.opt_end_index {
             if self.next_opt_index == opt.opt_index as i32 {
-                self.opt_bitmap |= opt.opt_result << self.next_opt_index;
+                // 根据玩家选择的答案判断对错，选中的答案和题目是同一句诗即为答对
+                let opt_result = if let Some(opt_sign) = opt.opt_sign {
+                    match poem_line {
+                        Some(poem_line) if poem_line.q_sign == opt_sign => 0,
+                        _ => 1,
+                    }
+                } else if context.trust_client_opt_result {
+                    if opt.opt_result == 0 {
+                        0
+                    } else {
+                        1
+                    }
+                } else {
+                    log::warn!(
+                        "Player {} OPT without opt_sign, treat as wrong!",
+                        self.player_id
+                    );
+                    1
+                };
+
+                self.opt_results.push(opt_result);
+                self.opt_timestamps.push(curr_timestamp);
                 log::info!("On Player {} OPT", self.player_name);
-                if opt.opt_result == 0 {
-                    // 在答对的情况下，计算得分
-                    let remaining_time = self.next_opt_timeout_timestamp - curr_timestamp;
+                let poem_mill_time = rules.poem_mill_time;
+                let remaining_time = self.next_opt_timeout_timestamp - curr_timestamp;
+                context.cheat_checker.check_opt(
+                    &self.player_id,
+                    &mut self.cheat_record,
+                    curr_timestamp - self.opt_start_timestamp,
+                    opt_result == 0,
+                );
+                if opt_result == 0 && self.next_opt_index < rules.poem_num as i32 {
+                    // 在答对的情况下，计算得分，加赛题目只比谁先答对，不计分
                     if remaining_time < 0 || remaining_time > poem_mill_time {
                         log::error!("逻辑错误，剩余时间不在合理范围内, {} ", remaining_time);
                     } else {
                         let remaining_percent = remaining_time as f64 / poem_mill_time as f64;
-                        let half_score = poem_score / 2;
+                        let half_score = context.poem_score / 2;
                         let got_score = half_score + (half_score as f64 * remaining_percent) as u32;
                         self.game_score += got_score;
                     }
                 }
                 self.next_opt_index += 1;
                 self.next_opt_timeout_timestamp =
-                    curr_timestamp + poem_mill_time + POEM_RESULT_WAIT;
+                    curr_timestamp + poem_mill_time + rules.poem_result_wait;
+                self.opt_start_timestamp = rules.next_opt_start(curr_timestamp);
                 self.is_dirty = true;
+                return Ok(());
             } else {
                 log::error!("OPT failed with index!");
             }
         }
+        context.cheat_checker.check_out_of_order(
+            &self.player_id,
+            &mut self.cheat_record,
+            opt.opt_index,
+            self.next_opt_index,
+        );
+        return Err(proto::ErrorCode::StaleOptIndex);
     }
 
-    fn update_robot_opt(&mut self, curr_timestamp: i64, poem_mill_time: i64, poem_score: u32) {
-        if self.next_opt_index < MATCH_POEM_NUM as i32 {
+    fn update_robot_opt(&mut self, curr_timestamp: i64, rules: &GameRules, poem_score: u32) {
+        if self.next_opt_index < self.opt_end_index {
+            let poem_mill_time = rules.poem_mill_time;
             if let Some(ref mut robot) = self.robot {
                 let next_opt_time = self.next_opt_timeout_timestamp - robot.next_early_opt_time;
                 if curr_timestamp >= next_opt_time {
                     // 执行机器人操作
                     let opt_result = robot.get_opt_result();
-                    self.opt_bitmap |= opt_result << self.next_opt_index;
+                    self.opt_results.push(opt_result);
+                    // 同一帧里有多个机器人作答时，按各自预定的作答时间比较先后
+                    self.opt_timestamps.push(next_opt_time);
                     self.is_dirty = true;
                     log::info!("ROBOT {} auto OPT!", self.player_name);
 
@@ -79,7 +210,7 @@ impl Player {
                         robot.next_early_opt_time as f64 / poem_mill_time as f64;
                     let half_score = poem_score / 2;
                     let got_score = half_score + (half_score as f64 * remaining_percent) as u32;
-                    if opt_result == 0 {
+                    if opt_result == 0 && self.next_opt_index < rules.poem_num as i32 {
                         // log::info!(">>>>>>>>>>>>>>> Robot Score: remainingPercent: {}, half_score: {}, got_score: {}",
                         //         remaining_percent, half_score, got_score);
                         self.game_score += got_score;
@@ -90,6 +221,7 @@ impl Player {
                     // 对于机器人来说，就不要下一首诗的等待时间了
                     self.next_opt_timeout_timestamp = curr_timestamp + poem_mill_time;
                     // + POEM_RESULT_WAIT;
+                    self.opt_start_timestamp = curr_timestamp;
                 }
                 // else {
                 //     log::error!(
@@ -104,13 +236,15 @@ impl Player {
         }
     }
 
-    fn update_opt_timeout_status(&mut self, curr_timestamp: i64, poem_mill_time: i64) {
-        if self.next_opt_index < MATCH_POEM_NUM as i32 {
+    fn update_opt_timeout_status(&mut self, curr_timestamp: i64, rules: &GameRules) {
+        if self.next_opt_index < self.opt_end_index {
             if curr_timestamp > self.next_opt_timeout_timestamp {
-                self.opt_bitmap |= 1 << self.next_opt_index;
+                self.opt_results.push(1);
+                self.opt_timestamps.push(curr_timestamp);
                 self.next_opt_index += 1;
                 self.next_opt_timeout_timestamp =
-                    curr_timestamp + poem_mill_time + POEM_RESULT_WAIT;
+                    curr_timestamp + rules.poem_mill_time + rules.poem_result_wait;
+                self.opt_start_timestamp = rules.next_opt_start(curr_timestamp);
                 self.is_dirty = true;
                 log::info!("Player {} OPT Timeout, Auto Failed!", self.player_name);
             }
@@ -125,31 +259,72 @@ impl Player {
 }
 
 struct Game {
-    id: String,           // 游戏ID
-    start_timestamp: i64, // 游戏开始时间戳
-    player1: Player,
-    player2: Player,
-    is_gaming: bool, // 游戏进行中
+    id: String,                     // 游戏ID
+    queue_id: String,               // 玩家所在的匹配队列
+    rules: GameRules,               // 本局的题目数和答题时间
+    start_timestamp: i64,           // 游戏开始时间戳
+    players: Vec<Player>,           // 1v1 时为两个玩家，多人对局时为 3 - 8 个
+    team_num: usize,                // 队伍数量，不组队时等于玩家数量
+    poem_data: Vec<PoemLineRecord>, // 本局的诗词数据，断线重连时需要重新发给客户端
+    is_gaming: bool,                // 游戏进行中
     is_dirty: bool,
+    end_type: i32,
+    is_rated: bool,               // 是否改变分数和等级，好友对战可以不改变
+    tiebreak_round: u32,          // 已经开始的加赛轮数，0 没有加赛
+    tiebreak_team: Option<usize>, // 加赛获胜的队伍
+    question_index: i32,          // 同步答题时当前这首诗的索引，-1 还没有开始
 }
 
 impl Game {
-    fn new(player1: Player, player2: Player, start_timestamp: i64) -> Self {
+    fn new(
+        queue_id: String,
+        rules: GameRules,
+        players: Vec<Player>,
+        poem_data: Vec<PoemLineRecord>,
+        start_timestamp: i64,
+        is_rated: bool,
+    ) -> Self {
+        let player_id_vec: Vec<&str> = players
+            .iter()
+            .map(|player| player.player_id.as_str())
+            .collect();
+        let team_num = players
+            .iter()
+            .map(|player| player.team_id + 1)
+            .max()
+            .unwrap_or(0);
         Self {
-            id: format!(
-                "{}_{}_{}",
-                player1.player_id, player2.player_id, start_timestamp
-            ),
+            id: format!("{}_{}", player_id_vec.join("_"), start_timestamp),
+            queue_id,
+            rules,
             start_timestamp,
-            player1,
-            player2,
+            players,
+            team_num,
+            poem_data,
             is_gaming: true,
             is_dirty: false,
+            end_type: END_TYPE_NORMAL,
+            is_rated,
+            tiebreak_round: 0,
+            tiebreak_team: None,
+            question_index: -1,
         }
     }
 
+    // 在线的玩家连接，用于同步消息
+    fn endpoint_ids(&self) -> Vec<String> {
+        self.players
+            .iter()
+            .filter_map(|player| player.endpoint_id.clone())
+            .collect()
+    }
+
     fn is_dirty(&mut self) -> bool {
-        let is_dirty = self.is_dirty || self.player1.is_dirty() || self.player2.is_dirty();
+        let mut is_dirty = self.is_dirty;
+        for player in self.players.iter_mut() {
+            // 每个玩家的标记都要清掉，不能短路
+            is_dirty |= player.is_dirty();
+        }
         self.is_dirty = false;
         return is_dirty;
     }
@@ -158,238 +333,990 @@ impl Game {
         &mut self,
         opt: proto::CGMatchGameOpt,
         curr_timestamp: i64,
-        poem_mill_time: i64,
         poem_score: u32,
-    ) {
-        if opt.id == self.player1.player_id {
-            self.player1
-                .on_opt(opt, curr_timestamp, poem_mill_time, poem_score);
-        } else if opt.id == self.player2.player_id {
-            self.player2
-                .on_opt(opt, curr_timestamp, poem_mill_time, poem_score);
+        trust_client_opt_result: bool,
+        cheat_checker: &mut CheatChecker,
+    ) -> Result<(), proto::ErrorCode> {
+        let poem_line = self.poem_data.get(opt.opt_index as usize);
+        if let Some(player) = self
+            .players
+            .iter_mut()
+            .find(|player| player.player_id == opt.id)
+        {
+            // 同步答题时不能提前回答下一首诗
+            if self.rules.lockstep && opt.opt_index as i32 != self.question_index {
+                log::error!(
+                    "Player {} OPT index {} not current question {}!",
+                    player.player_id,
+                    opt.opt_index,
+                    self.question_index
+                );
+                return Err(proto::ErrorCode::StaleOptIndex);
+            }
+            // 同步答题时上一首诗的结果还在显示，这首诗还没有开始
+            if self.rules.lockstep && curr_timestamp < player.opt_start_timestamp {
+                log::error!(
+                    "Player {} OPT index {} before question start {}!",
+                    player.player_id,
+                    opt.opt_index,
+                    player.opt_start_timestamp
+                );
+                return Err(proto::ErrorCode::StaleOptIndex);
+            }
+            let mut context = OptContext {
+                rules: &self.rules,
+                poem_score,
+                trust_client_opt_result,
+                cheat_checker,
+            };
+            player.on_opt(opt, curr_timestamp, poem_line, &mut context)?;
+            self.update_tiebreak_winner();
+            return Ok(());
         }
+        return Err(proto::ErrorCode::NotYourGame);
     }
 
-    // 更新游戏是否结束
-    fn update_end_status(&mut self) {
-        if self.player1.is_all_opt_end() && self.player2.is_all_opt_end() {
-            self.is_gaming = false;
-            self.is_dirty = true;
+    // 加赛中最早答对的玩家所在的队伍获胜
+    fn update_tiebreak_winner(&mut self) {
+        if self.tiebreak_round == 0 || self.tiebreak_team.is_some() {
+            return;
+        }
+        let opt_index = self.poem_data.len() as i32 - 1;
+        if let Some(player) = self
+            .players
+            .iter()
+            .filter(|player| player.is_opt_correct(opt_index))
+            .min_by_key(|player| player.opt_timestamps[opt_index as usize])
+        {
+            log::info!(
+                "Player {} win tiebreak round {} of game {}",
+                player.player_id,
+                self.tiebreak_round,
+                self.id
+            );
+            self.tiebreak_team = Some(player.team_id);
+        }
+    }
+
+    // 并列第一且没有整队判负的队伍
+    fn tied_first_teams(&self) -> Vec<usize> {
+        let team_ranks = self.team_ranks();
+        return (0..self.team_num)
+            .filter(|team| team_ranks[*team] == 1 && !self.is_team_forfeit(*team))
+            .collect();
+    }
+
+    // 更新游戏是否结束，所有玩家都答完后第一名并列并且可以加赛时，返回需要加赛的队伍
+    fn update_end_status(&mut self, tiebreak_config: &TiebreakConfig) -> Option<Vec<usize>> {
+        if !self.is_gaming {
+            return None;
+        }
+        if self.tiebreak_team.is_none() {
+            if !self.players.iter().all(|player| player.is_finished()) {
+                return None;
+            }
+            if tiebreak_config.enabled && self.tiebreak_round < tiebreak_config.max_rounds {
+                let tied_team_vec = self.tied_first_teams();
+                if tied_team_vec.len() > 1 {
+                    return Some(tied_team_vec);
+                }
+            }
+        }
+        self.is_gaming = false;
+        self.is_dirty = true;
+        return None;
+    }
+
+    // 开始新一轮加赛，并列的队伍中没有被判负的玩家再答一首诗，返回通知加赛的消息
+    fn start_tiebreak_round(
+        &mut self,
+        tied_teams: &[usize],
+        poem_line: PoemLineRecord,
+        curr_timestamp: i64,
+    ) -> proto::GCMessage {
+        self.tiebreak_round += 1;
+        // 加赛题目马上可以作答，同步答题时和其他题目一样等上一首的结果显示完
+        let start_timestamp = if self.rules.lockstep {
+            curr_timestamp + self.rules.poem_result_wait
+        } else {
+            curr_timestamp
+        };
+        let timeout_timestamp = start_timestamp + self.rules.poem_mill_time;
+        let opt_index = self.poem_data.len() as i32;
+        self.poem_data.push(poem_line.clone());
+
+        let mut player_id_vec: Vec<String> = Vec::new();
+        for player in self.players.iter_mut() {
+            if player.is_forfeit || !tied_teams.contains(&player.team_id) {
+                continue;
+            }
+            // 所有参加加赛的玩家同时开始答题
+            player.opt_end_index = opt_index + 1;
+            player.next_opt_timeout_timestamp = timeout_timestamp;
+            player.opt_start_timestamp = start_timestamp;
+            player_id_vec.push(player.player_id.clone());
         }
+        log::info!(
+            "Game {} tiebreak round {}, players: {:?}",
+            self.id,
+            self.tiebreak_round,
+            player_id_vec
+        );
+
+        let gc_tiebreak = proto::GCTiebreak {
+            game_id: self.id.clone(),
+            round: self.tiebreak_round,
+            opt_index: opt_index as u32,
+            poem_data: poem_line,
+            player_ids: player_id_vec,
+            remaining_time: timeout_timestamp - curr_timestamp,
+        };
+        return proto::ProtoData::gc_to_message(proto::PROTO_GCTIEBREAK, gc_tiebreak);
     }
 
     fn is_game_end(&self) -> bool {
         !self.is_gaming
     }
 
-    fn update_robot_opt(&mut self, curr_timestamp: i64, poem_mill_time: i64, poem_score: u32) {
-        self.player1
-            .update_robot_opt(curr_timestamp, poem_mill_time, poem_score);
-        self.player2
-            .update_robot_opt(curr_timestamp, poem_mill_time, poem_score);
+    // 同步答题时玩家答完当前这首诗后，要等下一首开始
+    fn is_waiting_question(&self, player: &Player) -> bool {
+        self.rules.lockstep && player.next_opt_index != self.question_index
+    }
+
+    fn update_robot_opt(&mut self, curr_timestamp: i64, poem_score: u32) {
+        for index in 0..self.players.len() {
+            if self.is_waiting_question(&self.players[index]) {
+                continue;
+            }
+            self.players[index].update_robot_opt(curr_timestamp, &self.rules, poem_score);
+        }
+    }
+
+    fn update_opt_timeout_status(&mut self, curr_timestamp: i64) {
+        for index in 0..self.players.len() {
+            if self.players[index].is_forfeit || self.is_waiting_question(&self.players[index]) {
+                continue;
+            }
+            self.players[index].update_opt_timeout_status(curr_timestamp, &self.rules);
+        }
+    }
+
+    // 同步答题：所有玩家都答完当前这首诗或者超时后，等显示结果的时间过去再一起开始下一首
+    // 开始下一首时返回 GCNextQuestion 消息
+    fn update_lockstep(&mut self, curr_timestamp: i64) -> Option<proto::GCMessage> {
+        if !self.rules.lockstep || !self.is_gaming {
+            return None;
+        }
+        let is_answering = |player: &Player, opt_index: i32| {
+            !player.is_finished() && player.next_opt_index == opt_index
+        };
+        if self
+            .players
+            .iter()
+            .any(|player| is_answering(player, self.question_index))
+        {
+            return None;
+        }
+        let next_index = self.question_index + 1;
+        if !self
+            .players
+            .iter()
+            .any(|player| is_answering(player, next_index))
+        {
+            return None;
+        }
+
+        // 第一首诗在游戏开始时就可以作答
+        let start_timestamp = if self.question_index < 0 {
+            curr_timestamp
+        } else {
+            curr_timestamp + self.rules.poem_result_wait
+        };
+        let timeout_timestamp = start_timestamp + self.rules.poem_mill_time;
+        self.question_index = next_index;
+        for player in self.players.iter_mut() {
+            if player.next_opt_index == next_index {
+                player.next_opt_timeout_timestamp = timeout_timestamp;
+                player.opt_start_timestamp = start_timestamp;
+            }
+        }
+        log::info!(
+            "Game {} next question {}, start at {}",
+            self.id,
+            next_index,
+            start_timestamp
+        );
+
+        let gc_next_question = proto::GCNextQuestion {
+            game_id: self.id.clone(),
+            opt_index: next_index as u32,
+            server_timestamp: curr_timestamp,
+            start_timestamp,
+            timeout_timestamp,
+        };
+        return Some(proto::ProtoData::gc_to_message(
+            proto::PROTO_GCNEXTQUESTION,
+            gc_next_question,
+        ));
     }
 
-    fn update_opt_timeout_status(&mut self, curr_timestamp: i64, poem_mill_time: i64) {
-        self.player1
-            .update_opt_timeout_status(curr_timestamp, poem_mill_time);
-        self.player2
-            .update_opt_timeout_status(curr_timestamp, poem_mill_time);
+    fn player_progress(player: &Player) -> proto::GamePlayerProgress {
+        proto::GamePlayerProgress {
+            id: player.player_id.clone(),
+            name: player.player_name.clone(),
+            team: player.team_id as u32,
+            next_opt_index: player.next_opt_index,
+            opt_results: player.opt_results.clone(),
+        }
     }
 
-    fn gc_update_to_json(&self) -> Option<String> {
+    fn gc_update_to_json(&self) -> proto::GCMessage {
+        let (player1, player2) = (&self.players[0], &self.players[1]);
         let gc_update_game = proto::GCUpdateGame {
             game_id: self.id.clone(),
-            player1_id: self.player1.player_id.clone(),
-            player1_name: self.player1.player_name.clone(),
-            player1_next_opt_index: self.player1.next_opt_index,
-            player1_opt_bitmap: self.player1.opt_bitmap,
-            player2_id: self.player2.player_id.clone(),
-            player2_name: self.player2.player_name.clone(),
-            player2_next_opt_index: self.player2.next_opt_index,
-            player2_opt_bitmap: self.player2.opt_bitmap,
+            player1_id: player1.player_id.clone(),
+            player1_name: player1.player_name.clone(),
+            player1_next_opt_index: player1.next_opt_index,
+            player1_opt_bitmap: player1.opt_bitmap(),
+            player2_id: player2.player_id.clone(),
+            player2_name: player2.player_name.clone(),
+            player2_next_opt_index: player2.next_opt_index,
+            player2_opt_bitmap: player2.opt_bitmap(),
+            players: self.players.iter().map(Self::player_progress).collect(),
         };
 
-        return proto::ProtoData::gc_to_json_string(proto::PROTO_GCUPDATEGAME, gc_update_game);
+        return proto::ProtoData::gc_to_message(proto::PROTO_GCUPDATEGAME, gc_update_game);
     }
 
-    fn gc_end_game_to_json(&mut self, petable: &PETable) -> Option<String> {
-        if self.player1.game_score > self.player2.game_score {
-            self.player1.player_level += 1;
-        } else if self.player2.game_score > self.player1.game_score {
-            self.player2.player_level += 1;
+    // 连接断开，清掉失效的 endpoint，等待玩家重连
+    fn on_disconnect(&mut self, endpoint_id: &str, curr_timestamp: i64) -> bool {
+        let mut found = false;
+        for player in self.players.iter_mut() {
+            if player.endpoint_id.as_deref() == Some(endpoint_id) {
+                log::info!(
+                    "Player {} disconnected from game {}",
+                    player.player_id,
+                    self.id
+                );
+                player.endpoint_id = None;
+                player.disconnect_timestamp = Some(curr_timestamp);
+                found = true;
+            }
+        }
+        return found;
+    }
+
+    // 断线超过等待时间的玩家，按配置判负或交给机器人
+    fn update_disconnect_status(
+        &mut self,
+        curr_timestamp: i64,
+        grace_time: i64,
+        policy: DisconnectPolicy,
+        robot_ctrl: &mut RobotController,
+    ) {
+        for player in self.players.iter_mut() {
+            if player.robot.is_some() || player.is_forfeit || player.is_all_opt_end() {
+                continue;
+            }
+
+            if let Some(disconnect_timestamp) = player.disconnect_timestamp {
+                if curr_timestamp - disconnect_timestamp > grace_time {
+                    match policy {
+                        DisconnectPolicy::Forfeit => {
+                            log::info!(
+                                "Player {} disconnect timeout, forfeit game {}",
+                                player.player_id,
+                                self.id
+                            );
+                            player.is_forfeit = true;
+                            player.is_dirty = true;
+                        }
+                        DisconnectPolicy::Robot => {
+                            log::info!(
+                                "Player {} disconnect timeout, ROBOT takeover game {}",
+                                player.player_id,
+                                self.id
+                            );
+                            player.robot = Some(robot_ctrl.get_robot(
+                                player.player_level,
+                                player.player_elo_score,
+                                player.player_correct_rate,
+                                self.rules.poem_mill_time,
+                            ));
+                            player.is_robot_takeover = true;
+                        }
+                    }
+                }
+            }
         }
 
-        let (ea, eb, _) =
-            petable.get_ea_eb(self.player1.player_elo_score, self.player2.player_elo_score);
-        let (player1_sa, player2_sa) = if self.player1.game_score > self.player2.game_score {
-            (1.0, 0.0)
-        } else if self.player1.game_score < self.player2.game_score {
-            (0.0, 1.0)
+        let forfeit_num = self
+            .players
+            .iter()
+            .filter(|player| player.is_forfeit)
+            .count();
+        let active_team_num = (0..self.team_num)
+            .filter(|team| !self.is_team_forfeit(*team))
+            .count();
+        self.end_type = if forfeit_num == 0 {
+            END_TYPE_NORMAL
+        } else if active_team_num == 0 {
+            END_TYPE_BOTH_FORFEIT
+        } else if self.players.len() > 2 {
+            END_TYPE_PLAYER_FORFEIT
+        } else if self.players[0].is_forfeit {
+            END_TYPE_PLAYER1_FORFEIT
         } else {
-            (0.5, 0.5)
+            END_TYPE_PLAYER2_FORFEIT
         };
+        // 只剩一队还有玩家没有被判负，游戏直接结束
+        if forfeit_num > 0 && active_team_num <= 1 && self.is_gaming {
+            self.is_gaming = false;
+            self.is_dirty = true;
+        }
+    }
+
+    fn gc_resume_game_to_json(
+        &mut self,
+        endpoint_id: String,
+        player_id: &str,
+        curr_timestamp: i64,
+        robot_ctrl: &mut RobotController,
+    ) -> Option<proto::GCMessage> {
+        let game_id = self.id.clone();
+        let player = self
+            .players
+            .iter_mut()
+            .find(|player| player.player_id == player_id)?;
+
+        // 机器人不会重连
+        if player.is_robot() {
+            return None;
+        }
+
+        // 已经判负的玩家不能回到游戏继续答题
+        if player.is_forfeit {
+            log::warn!(
+                "Player {} resume game {} after forfeit!",
+                player_id,
+                game_id
+            );
+            return Some(proto::ProtoData::gc_to_message(
+                proto::PROTO_GCRESUMEGAME,
+                proto::GCResumeGame {
+                    code: RESUME_GAME_FORFEIT,
+                    game_id,
+                    ..Default::default()
+                },
+            ));
+        }
+
+        // 断线期间被机器人接管了，重连后还给玩家
+        if player.is_robot_takeover {
+            if let Some(robot) = player.robot.take() {
+                robot_ctrl.back_robot(&robot);
+            }
+            player.is_robot_takeover = false;
+        }
+        player.disconnect_timestamp = None;
+
         log::info!(
-            "ea = {}, eb = {}, player1_sa = {}, player2_sa = {}",
-            ea,
-            eb,
-            player1_sa,
-            player2_sa,
+            "Player {} resume game {}, endpoint: {:?} -> {}",
+            player_id,
+            game_id,
+            player.endpoint_id,
+            endpoint_id
         );
+        player.endpoint_id = Some(endpoint_id);
+        let is_waiting_question =
+            self.rules.lockstep && player.next_opt_index != self.question_index;
+        let remaining_time = if player.is_all_opt_end() || is_waiting_question {
+            0
+        } else {
+            (player.next_opt_timeout_timestamp - curr_timestamp).max(0)
+        };
+
+        let (player1, player2) = (&self.players[0], &self.players[1]);
+        let gc_resume_game = proto::GCResumeGame {
+            code: 0,
+            game_id,
+            player1_id: player1.player_id.clone(),
+            player1_name: player1.player_name.clone(),
+            player1_next_opt_index: player1.next_opt_index,
+            player1_opt_bitmap: player1.opt_bitmap(),
+            player2_id: player2.player_id.clone(),
+            player2_name: player2.player_name.clone(),
+            player2_next_opt_index: player2.next_opt_index,
+            player2_opt_bitmap: player2.opt_bitmap(),
+            players: self.players.iter().map(Self::player_progress).collect(),
+            poem_data: self.poem_data.clone(),
+            remaining_time,
+            tiebreak_round: self.tiebreak_round,
+            poem_num: self.rules.poem_num,
+            poem_mill_time: self.rules.poem_mill_time,
+            poem_result_wait: self.rules.poem_result_wait,
+            lockstep: self.rules.lockstep,
+        };
+
+        return Some(proto::ProtoData::gc_to_message(
+            proto::PROTO_GCRESUMEGAME,
+            gc_resume_game,
+        ));
+    }
+
+    fn team_members(&self, team: usize) -> impl Iterator<Item = &Player> {
+        self.players
+            .iter()
+            .filter(move |player| player.team_id == team)
+    }
+
+    // 队伍得分为队员得分之和
+    fn team_score(&self, team: usize) -> u32 {
+        self.team_members(team)
+            .map(|player| player.game_score)
+            .sum()
+    }
+
+    fn team_correct_count(&self, team: usize) -> u32 {
+        self.team_members(team)
+            .map(|player| player.correct_count(self.rules.poem_num))
+            .sum()
+    }
+
+    // 队伍里所有玩家都被判负才算整队判负
+    fn is_team_forfeit(&self, team: usize) -> bool {
+        self.team_members(team).all(|player| player.is_forfeit)
+    }
+
+    // 两个队伍之间的比赛结果，1 胜，0 负，0.5 平
+    fn pair_result(&self, team_i: usize, team_j: usize) -> (f64, f64) {
+        match (self.is_team_forfeit(team_i), self.is_team_forfeit(team_j)) {
+            (true, true) => (0.0, 0.0),
+            (true, false) => (0.0, 1.0),
+            (false, true) => (1.0, 0.0),
+            (false, false) => {
+                let (score_i, score_j) = (self.team_score(team_i), self.team_score(team_j));
+                if score_i > score_j {
+                    (1.0, 0.0)
+                } else if score_i < score_j {
+                    (0.0, 1.0)
+                } else if self.tiebreak_team == Some(team_i) {
+                    (1.0, 0.0)
+                } else if self.tiebreak_team == Some(team_j) {
+                    (0.0, 1.0)
+                } else {
+                    (0.5, 0.5)
+                }
+            }
+        }
+    }
+
+    // 每两个队伍之间的实际得分，都没有整队判负的按胜负差距换算
+    // 不组队时每个玩家单独一队，就是每两个玩家之间的实际得分
+    fn team_pair_scores(&self, margin_config: &MarginConfig) -> Vec<(usize, usize, f64, f64)> {
+        let mut pair_score_vec: Vec<(usize, usize, f64, f64)> = Vec::new();
+        for i in 0..self.team_num {
+            for j in (i + 1)..self.team_num {
+                let result = self.pair_result(i, j);
+                let (score_i, score_j) = if self.is_team_forfeit(i) || self.is_team_forfeit(j) {
+                    result
+                } else {
+                    let (game_score_i, game_score_j) = (self.team_score(i), self.team_score(j));
+                    let (correct_i, correct_j) =
+                        (self.team_correct_count(i), self.team_correct_count(j));
+                    // 队伍的题目数为每个队员答题数之和
+                    let member_num = self
+                        .team_members(i)
+                        .count()
+                        .max(self.team_members(j).count())
+                        .max(1) as u32;
+                    margin_config.actual_scores(
+                        result,
+                        game_score_i.max(game_score_j) - game_score_i.min(game_score_j),
+                        correct_i.max(correct_j) - correct_i.min(correct_j),
+                        self.rules.poem_num * member_num,
+                    )
+                };
+                pair_score_vec.push((i, j, score_i, score_j));
+            }
+        }
+        return pair_score_vec;
+    }
 
-        let player1_new_elo_score: u32 =
-            (self.player1.player_elo_score as f64 + 32.0 * (player1_sa - ea)) as u32;
+    // 每个队伍和所有对手队伍的实际得分的平均值，队员的实际得分和队伍相同
+    fn average_scores(&self, team_pair_scores: &[(usize, usize, f64, f64)]) -> Vec<f64> {
+        let mut sum_vec: Vec<f64> = vec![0.0; self.team_num];
+        for &(i, j, score_i, score_j) in team_pair_scores.iter() {
+            sum_vec[i] += score_i;
+            sum_vec[j] += score_j;
+        }
+        let opponent_num = (self.team_num - 1).max(1) as f64;
+        return sum_vec.iter().map(|sum| sum / opponent_num).collect();
+    }
 
-        let player2_new_elo_score: u32 =
-            (self.player2.player_elo_score as f64 + 32.0 * (player2_sa - eb)) as u32;
+    // 队伍名次，得分高的在前，整队判负的排在最后，得分相同的加赛获胜的在前，否则名次相同
+    fn team_ranks(&self) -> Vec<u32> {
+        let rank_key = |team: usize| {
+            (
+                !self.is_team_forfeit(team),
+                self.team_score(team),
+                self.tiebreak_team == Some(team),
+            )
+        };
+        return (0..self.team_num)
+            .map(|team| {
+                let better_num = (0..self.team_num)
+                    .filter(|other| rank_key(*other) > rank_key(team))
+                    .count();
+                better_num as u32 + 1
+            })
+            .collect();
+    }
 
+    fn player_rating(player: &Player) -> PlayerRating {
+        PlayerRating {
+            elo_score: player.player_elo_score,
+            level: player.player_level,
+            rated_games: player.player_rated_games,
+            deviation: player.player_rating_deviation,
+            volatility: player.player_rating_volatility,
+        }
+    }
+
+    // 唯一的第一名队伍里的玩家升级，并按分数系统和每个对手队伍计算新的分数
+    fn update_ratings(
+        &mut self,
+        rating_system: &dyn RatingSystem,
+        team_pair_scores: &[(usize, usize, f64, f64)],
+        team_ranks: &[u32],
+        void_elo: bool,
+    ) {
+        if team_ranks.iter().filter(|rank| **rank == 1).count() == 1 {
+            for player in self.players.iter_mut() {
+                if team_ranks[player.team_id] == 1 {
+                    player.player_level += 1;
+                }
+            }
+        }
+
+        if void_elo {
+            log::warn!("Game {} elo voided for cheating!", self.id);
+        } else {
+            let rating_vec: Vec<PlayerRating> =
+                self.players.iter().map(Self::player_rating).collect();
+            let player_team_vec: Vec<usize> =
+                self.players.iter().map(|player| player.team_id).collect();
+            let new_rating_vec = rating::update_teams(
+                rating_system,
+                &rating_vec,
+                &player_team_vec,
+                team_pair_scores,
+            );
+            for (player, new_rating) in self.players.iter_mut().zip(new_rating_vec) {
+                player.player_elo_score = new_rating.elo_score;
+                player.player_rated_games += 1;
+                player.player_rating_deviation = new_rating.deviation;
+                player.player_rating_volatility = new_rating.volatility;
+            }
+        }
+    }
+
+    // 计算新的分数和等级，结果保存在玩家身上，之后由服务器写回 Redis
+    // void_elo: 本局有玩家被标记作弊，不改变 elo 分
+    fn gc_end_game_to_json(
+        &mut self,
+        rating_system: &dyn RatingSystem,
+        margin_config: &MarginConfig,
+        void_elo: bool,
+    ) -> proto::GCMessage {
+        let team_pair_scores = self.team_pair_scores(margin_config);
+        let team_actual_scores = self.average_scores(&team_pair_scores);
+        let team_ranks = self.team_ranks();
+        if self.is_rated {
+            self.update_ratings(rating_system, &team_pair_scores, &team_ranks, void_elo);
+        } else {
+            log::info!("Game {} is not rated", self.id);
+        }
+
+        let player_result_vec: Vec<proto::GamePlayerResult> = self
+            .players
+            .iter()
+            .map(|player| proto::GamePlayerResult {
+                id: player.player_id.clone(),
+                name: player.player_name.clone(),
+                team: player.team_id as u32,
+                opt_results: player.opt_results.clone(),
+                game_score: player.game_score,
+                new_elo_score: player.player_elo_score,
+                new_level: player.player_level,
+                actual_score: team_actual_scores[player.team_id],
+                rank: team_ranks[player.team_id],
+                is_forfeit: player.is_forfeit,
+            })
+            .collect();
+        let team_result_vec: Vec<proto::GameTeamResult> = (0..self.team_num)
+            .map(|team| proto::GameTeamResult {
+                team: team as u32,
+                game_score: self.team_score(team),
+                actual_score: team_actual_scores[team],
+                rank: team_ranks[team],
+            })
+            .collect();
+
+        let (player1, player2) = (&self.players[0], &self.players[1]);
         let gc_end_game = proto::GCEndGame {
             game_id: self.id.clone(),
-            player1_id: self.player1.player_id.clone(),
-            player1_name: self.player1.player_name.clone(),
-            player1_opt_bitmap: self.player1.opt_bitmap,
-            player1_game_score: self.player1.game_score,
-            player1_new_elo_score: player1_new_elo_score,
-            player1_new_level: self.player1.player_level,
-            player2_id: self.player2.player_id.clone(),
-            player2_name: self.player2.player_name.clone(),
-            player2_opt_bitmap: self.player2.opt_bitmap,
-            player2_game_score: self.player2.game_score,
-            player2_new_elo_score: player2_new_elo_score,
-            player2_new_level: self.player2.player_level,
+            player1_id: player1.player_id.clone(),
+            player1_name: player1.player_name.clone(),
+            player1_opt_bitmap: player1.opt_bitmap(),
+            player1_game_score: player1.game_score,
+            player1_new_elo_score: player1.player_elo_score,
+            player1_new_level: player1.player_level,
+            player1_actual_score: team_actual_scores[player1.team_id],
+            player2_id: player2.player_id.clone(),
+            player2_name: player2.player_name.clone(),
+            player2_opt_bitmap: player2.opt_bitmap(),
+            player2_game_score: player2.game_score,
+            player2_new_elo_score: player2.player_elo_score,
+            player2_new_level: player2.player_level,
+            player2_actual_score: team_actual_scores[player2.team_id],
+            players: player_result_vec,
+            teams: team_result_vec,
+            end_type: self.end_type,
+            tiebreak_rounds: self.tiebreak_round,
+            tiebreak_team: self.tiebreak_team.map_or(-1, |team| team as i32),
         };
 
-        return proto::ProtoData::gc_to_json_string(proto::PROTO_GCENDGAME, gc_end_game);
+        return proto::ProtoData::gc_to_message(proto::PROTO_GCENDGAME, gc_end_game);
     }
 }
 
+// 每个匹配队列的游戏使用自己的诗词表、机器人表、玩法规则和分数系统
+struct GameQueue {
+    poem_table: PoemTable,
+    robot_ctrl: RobotController,
+    rules: GameRules,
+    rating_system: Box<dyn RatingSystem>,
+    margin_config: MarginConfig,
+    tiebreak_config: TiebreakConfig,
+}
+
+// 服务器的游戏设置，队列没有单独配置的答题时间、胜负差距和加赛规则使用这里的
+pub struct GameSettings {
+    pub poem_mill_time: i64,
+    pub poem_score: u32,
+    pub disconnect_grace_time: i64, // 断线后等待重连的时间，ms
+    pub disconnect_policy: DisconnectPolicy,
+    pub trust_client_opt_result: bool,
+    pub anti_cheat_config: AntiCheatConfig,
+    pub margin_config: MarginConfig,
+    pub tiebreak_config: TiebreakConfig,
+}
+
 pub struct MatchGameController {
     game_map: HashMap<String, Game>,
     ended_game: Vec<String>,
-    poem_table: PoemTable,
-    petable: PETable,
-    robot_ctrl: RobotController,
+    ended_player: Vec<String>, // 本帧结束的游戏中的真实玩家，用于清理玩家状态
+    queue_map: HashMap<String, GameQueue>, // 队列ID - 诗词表、机器人表、玩法规则和分数系统
     tx: std::sync::mpsc::Sender<RedisOpt>,
-    poem_mill_time: i64,
     poem_score: u32,
+    disconnect_grace_time: i64,
+    disconnect_policy: DisconnectPolicy,
+    trust_client_opt_result: bool,
+    cheat_checker: CheatChecker,
 }
 
 impl MatchGameController {
+    // rating_system_map: 队列ID - 该队列的分数系统
     pub fn new(
         tx: std::sync::mpsc::Sender<RedisOpt>,
-        poem_mill_time: i64,
-        poem_score: u32,
+        queue_config_vec: &[MatchQueueConfig],
+        mut rating_system_map: HashMap<String, Box<dyn RatingSystem>>,
+        settings: GameSettings,
     ) -> Self {
+        let mut queue_map: HashMap<String, GameQueue> = HashMap::new();
+        for queue_config in queue_config_vec.iter() {
+            let queue_id = queue_config.queue_id();
+            let rating_system = match rating_system_map.remove(&queue_id) {
+                Some(rating_system) => rating_system,
+                None => {
+                    log::error!("Game queue {} has no rating system, skipped", queue_id);
+                    continue;
+                }
+            };
+            let poem_table = PoemTable::new(&queue_config.poem_table);
+            // 一局中的诗词不重复，题目数不能超过诗词表中的诗词数
+            let poem_num = queue_config.poem_num.clamp(1, poem_table.count.max(1));
+            if poem_num != queue_config.poem_num {
+                log::warn!(
+                    "Game queue {} poem_num {} clamped to {}, poem table has {} poems",
+                    queue_id,
+                    queue_config.poem_num,
+                    poem_num,
+                    poem_table.count
+                );
+            }
+            queue_map.insert(
+                queue_id,
+                GameQueue {
+                    poem_table,
+                    robot_ctrl: RobotController::new(&queue_config.robot_table),
+                    rules: GameRules {
+                        poem_num,
+                        poem_mill_time: queue_config
+                            .poem_mill_time
+                            .unwrap_or(settings.poem_mill_time),
+                        poem_result_wait: queue_config.poem_result_wait,
+                        lockstep: queue_config.lockstep,
+                    },
+                    rating_system,
+                    margin_config: queue_config
+                        .margin_of_victory
+                        .clone()
+                        .unwrap_or_else(|| settings.margin_config.clone()),
+                    tiebreak_config: queue_config
+                        .tiebreak
+                        .clone()
+                        .unwrap_or_else(|| settings.tiebreak_config.clone()),
+                },
+            );
+        }
+
         Self {
+            cheat_checker: CheatChecker::new(settings.anti_cheat_config, tx.clone()),
             game_map: HashMap::new(),
             ended_game: Vec::new(),
-            poem_table: PoemTable::new(),
-            petable: PETable::new(),
-            robot_ctrl: RobotController::new(),
+            ended_player: Vec::new(),
+            queue_map,
             tx,
-            poem_mill_time,
-            poem_score,
+            poem_score: settings.poem_score,
+            disconnect_grace_time: settings.disconnect_grace_time,
+            disconnect_policy: settings.disconnect_policy,
+            trust_client_opt_result: settings.trust_client_opt_result,
         }
     }
 
-    pub fn on_opt(&mut self, opt_info: proto::CGMatchGameOpt, curr_timestamp: i64) {
+    pub fn on_opt(
+        &mut self,
+        opt_info: proto::CGMatchGameOpt,
+        curr_timestamp: i64,
+    ) -> Result<(), proto::ErrorCode> {
         if let Some(game) = self.game_map.get_mut(&opt_info.game_id) {
-            game.on_opt(
+            return game.on_opt(
                 opt_info,
                 curr_timestamp,
-                self.poem_mill_time,
                 self.poem_score,
+                self.trust_client_opt_result,
+                &mut self.cheat_checker,
             );
         }
+        return Err(proto::ErrorCode::UnknownGame);
+    }
+
+    pub fn on_disconnect(&mut self, endpoint_id: &str, curr_timestamp: i64) {
+        for (_, game) in self.game_map.iter_mut() {
+            if game.on_disconnect(endpoint_id, curr_timestamp) {
+                break;
+            }
+        }
+    }
+
+    // 断线重连，将玩家重新绑定到新的连接上，返回给该连接的回复消息
+    pub fn resume_game(
+        &mut self,
+        endpoint_id: String,
+        resume_info: proto::CGResumeGame,
+        curr_timestamp: i64,
+    ) -> proto::GCMessage {
+        if let Some(game) = self.game_map.get_mut(&resume_info.game_id) {
+            if let Some(game_queue) = self.queue_map.get_mut(&game.queue_id) {
+                if !game.is_game_end() {
+                    if let Some(proto_json_str) = game.gc_resume_game_to_json(
+                        endpoint_id,
+                        &resume_info.id,
+                        curr_timestamp,
+                        &mut game_queue.robot_ctrl,
+                    ) {
+                        return proto_json_str;
+                    }
+                }
+            }
+        }
+
+        log::warn!(
+            "Player {} resume game {} failed!",
+            resume_info.id,
+            resume_info.game_id
+        );
+        return proto::ProtoData::gc_to_message(
+            proto::PROTO_GCRESUMEGAME,
+            proto::GCResumeGame {
+                code: RESUME_GAME_NOT_FOUND,
+                game_id: resume_info.game_id,
+                ..Default::default()
+            },
+        );
     }
 
     pub fn update_games(&mut self, curr_timestamp: i64) -> Option<Vec<Signal>> {
         self.ended_game.clear();
+        self.ended_player.clear();
         let mut some_signal_vec: Option<Vec<Signal>> = None;
         for (_, game) in self.game_map.iter_mut() {
-            game.update_robot_opt(curr_timestamp, self.poem_mill_time, self.poem_score);
-            game.update_opt_timeout_status(curr_timestamp, self.poem_mill_time);
-            game.update_end_status();
-
-            if game.is_dirty() {
-                log::info!("Game {} data is dirty!", game.id);
-                if let Some(proto_json_str) = game.gc_update_to_json() {
-                    log::info!("Sync GCUpdateGame {} data -> Client!", game.id);
-                    let signal = Signal::Sync(
-                        game.player1.endpoint_id.clone(),
-                        game.player2.endpoint_id.clone(),
-                        proto_json_str,
-                    );
+            let game_queue = match self.queue_map.get_mut(&game.queue_id) {
+                Some(game_queue) => game_queue,
+                None => {
+                    log::error!("Game {} in unknown queue {}", game.id, game.queue_id);
+                    continue;
+                }
+            };
+            game.update_disconnect_status(
+                curr_timestamp,
+                self.disconnect_grace_time,
+                self.disconnect_policy,
+                &mut game_queue.robot_ctrl,
+            );
+            game.update_robot_opt(curr_timestamp, self.poem_score);
+            game.update_opt_timeout_status(curr_timestamp);
+            game.update_tiebreak_winner();
+            if let Some(tied_team_vec) = game.update_end_status(&game_queue.tiebreak_config) {
+                // 每轮加赛从诗词表中再抽一首本局没有出过的诗
+                let some_poem_line = game_queue
+                    .poem_table
+                    .get_random_line_excluding(&game.poem_data);
+                let some_proto_json_str = if let Some(poem_line) = some_poem_line {
+                    Some(game.start_tiebreak_round(&tied_team_vec, poem_line, curr_timestamp))
+                } else {
+                    log::error!("加赛诗词数据生成失败，游戏 {} 按平局结束!", game.id);
+                    game.is_gaming = false;
+                    game.is_dirty = true;
+                    None
+                };
+                if let Some(proto_json_str) = some_proto_json_str {
+                    log::info!("Sync GCTiebreak {} data -> Client!", game.id);
+                    let signal = Signal::Sync(game.endpoint_ids(), proto_json_str);
 
                     if let Some(ref mut signal_vec) = some_signal_vec {
                         signal_vec.push(signal);
                     } else {
-                        let mut vec: Vec<Signal> = Vec::new();
-                        vec.push(signal);
-                        some_signal_vec = Some(vec);
+                        some_signal_vec = Some(vec![signal]);
                     }
                 }
             }
 
+            if game.is_dirty() {
+                log::info!("Game {} data is dirty!", game.id);
+                let proto_json_str = game.gc_update_to_json();
+                log::info!("Sync GCUpdateGame {} data -> Client!", game.id);
+                let signal = Signal::Sync(game.endpoint_ids(), proto_json_str);
+
+                if let Some(ref mut signal_vec) = some_signal_vec {
+                    signal_vec.push(signal);
+                } else {
+                    let mut vec: Vec<Signal> = Vec::new();
+                    vec.push(signal);
+                    some_signal_vec = Some(vec);
+                }
+            }
+
+            // 上一首的结果同步后，再通知开始下一首
+            if let Some(proto_json_str) = game.update_lockstep(curr_timestamp) {
+                log::info!("Sync GCNextQuestion {} data -> Client!", game.id);
+                let signal = Signal::Sync(game.endpoint_ids(), proto_json_str);
+
+                if let Some(ref mut signal_vec) = some_signal_vec {
+                    signal_vec.push(signal);
+                } else {
+                    some_signal_vec = Some(vec![signal]);
+                }
+            }
+
             if game.is_game_end() {
                 log::info!("Game {} is END!", game.id);
                 self.ended_game.push(game.id.clone());
+                for player in game.players.iter() {
+                    if !player.is_robot() {
+                        self.ended_player.push(player.player_id.clone());
+                    }
+                }
 
-                if let Some(player1_robot) = &game.player1.robot {
-                    self.robot_ctrl.back_robot(player1_robot);
+                for player in game.players.iter() {
+                    if let Some(robot) = &player.robot {
+                        game_queue.robot_ctrl.back_robot(robot);
+                    }
                 }
 
-                if let Some(player2_robot) = &game.player2.robot {
-                    self.robot_ctrl.back_robot(player2_robot);
+                for player in game.players.iter() {
+                    if self.cheat_checker.is_flagged(&player.cheat_record) {
+                        log::warn!(
+                            "Player {} flagged in game {}, game suspicion: {}",
+                            player.player_id,
+                            game.id,
+                            player.cheat_record.suspicion
+                        );
+                    }
                 }
+                let cheat_record_vec: Vec<&CheatRecord> = game
+                    .players
+                    .iter()
+                    .map(|player| &player.cheat_record)
+                    .collect();
+                let void_elo = self.cheat_checker.is_elo_voided(&cheat_record_vec);
+                let proto_json_str = game.gc_end_game_to_json(
+                    game_queue.rating_system.as_ref(),
+                    &game_queue.margin_config,
+                    void_elo,
+                );
+                log::info!("Sync GCEndGame {} END data -> Client!", game.id);
+                let signal = Signal::Sync(game.endpoint_ids(), proto_json_str);
 
-                if let Some(proto_json_str) = game.gc_end_game_to_json(&self.petable) {
-                    log::info!("Sync GCEndGame {} END data -> Client!", game.id);
-                    let signal = Signal::Sync(
-                        game.player1.endpoint_id.clone(),
-                        game.player2.endpoint_id.clone(),
-                        proto_json_str,
-                    );
+                if let Some(ref mut signal_vec) = some_signal_vec {
+                    signal_vec.push(signal);
+                } else {
+                    let mut vec: Vec<Signal> = Vec::new();
+                    vec.push(signal);
+                    some_signal_vec = Some(vec);
+                }
 
-                    if let Some(ref mut signal_vec) = some_signal_vec {
-                        signal_vec.push(signal);
-                    } else {
-                        let mut vec: Vec<Signal> = Vec::new();
-                        vec.push(signal);
-                        some_signal_vec = Some(vec);
-                    }
+                // 不改变分数的对局，不需要保存
+                if !game.is_rated {
+                    continue;
                 }
 
                 // 将玩家的id和名字，以及分数发到另一线程，用于存到Redis里
-                if let Ok(()) = self.tx.send(RedisOpt::GamePlayerData(
-                    format!("{}_{}", &game.player1.player_id, &game.player1.player_name),
-                    game.player1.player_level,
-                )) {
-                    // data send ok
-                    log::info!(
-                        "Player1 {}, name: {}, level {}, data is Sync to Redis",
-                        game.player1.player_id,
-                        game.player1.player_name,
-                        game.player1.player_level
-                    );
-                } else {
-                    // Channel send msg error
-                    log::error!("Send player1 name level to Redis failed!");
+                for (index, player) in game.players.iter().enumerate() {
+                    if let Ok(()) = self.tx.send(RedisOpt::GamePlayerData(
+                        format!("{}_{}", &player.player_id, &player.player_name),
+                        player.player_level,
+                    )) {
+                        // data send ok
+                        log::info!(
+                            "Player{} {}, name: {}, level {}, data is Sync to Redis",
+                            index + 1,
+                            player.player_id,
+                            player.player_name,
+                            player.player_level
+                        );
+                    } else {
+                        // Channel send msg error
+                        log::error!("Send player{} name level to Redis failed!", index + 1);
+                    }
                 }
-                // 将第2个玩家的数据也发到另一线程，存Redis
-                if let Ok(()) = self.tx.send(RedisOpt::GamePlayerData(
-                    format!("{}_{}", &game.player2.player_id, &game.player2.player_name),
-                    game.player2.player_level,
-                )) {
-                    // data send ok
-                    log::info!(
-                        "Player2 {}, name: {}, level {}, data is Sync to Redis",
-                        game.player2.player_id,
-                        game.player2.player_name,
-                        game.player2.player_level
-                    );
-                } else {
-                    // Channel send msg error
-                    log::error!("Send player2 name level to Redis failed!");
+
+                // 由服务器保存真实玩家新的分数和等级，下次匹配时读取
+                for player in game.players.iter() {
+                    if player.is_robot() {
+                        continue;
+                    }
+                    let rating = Game::player_rating(player);
+                    if let Ok(()) = self.tx.send(RedisOpt::SavePlayerRating(
+                        game.queue_id.clone(),
+                        player.player_id.clone(),
+                        rating,
+                    )) {
+                    } else {
+                        log::error!("Send player {} rating to Redis failed!", player.player_id);
+                    }
                 }
             }
         }
@@ -407,64 +1334,97 @@ impl MatchGameController {
         return some_signal_vec;
     }
 
+    // 上一次 update_games 中结束的游戏里的真实玩家ID
+    pub fn ended_players(&self) -> &Vec<String> {
+        &self.ended_player
+    }
+
+    // 成功时返回游戏ID和开始游戏的同步消息，is_rated 为 false 时不改变分数和等级
+    // 1v1 为两个玩家，多人对局时为 3 - 8 个，team_size 大于 1 时按分数分成人数相同的队伍
     pub fn start_new_game(
         &mut self,
-        mut player1: Player,
-        mut player2: Player,
+        queue_id: &str,
+        mut players: Vec<Player>,
+        team_size: usize,
         curr_timestamp: i64,
-    ) -> Option<Signal> {
-        player1.next_opt_index = 0;
-        player1.next_opt_timeout_timestamp = curr_timestamp + self.poem_mill_time + 1;
-        player2.next_opt_index = 0;
-        player2.next_opt_timeout_timestamp = curr_timestamp + self.poem_mill_time + 1;
-
-        log::info!("Try Start a new Game!");
-        let player_level = player1.player_level;
-        if let Some(poem_data_vec) = self
-            .poem_table
-            .get_random_game_data(player_level, MATCH_POEM_NUM)
-        {
-            let player1_id = player1.player_id.clone();
-            let player1_name = player1.player_name.clone();
-            let player2_id = player2.player_id.clone();
-            let player2_name = player2.player_name.clone();
-
-            let game_id = format!(
-                "{}_{}_{}",
-                player1.player_id.clone(),
-                player2.player_id.clone(),
-                curr_timestamp
-            );
+        is_rated: bool,
+    ) -> Option<(String, Signal)> {
+        if players.len() < 2 {
+            log::error!("At least 2 players to start a game: {:?}", players);
+            return None;
+        }
+        let elo_score_vec: Vec<i32> = players
+            .iter()
+            .map(|player| player.player_elo_score)
+            .collect();
+        log::info!("Try Start a new Game in queue {}!", queue_id);
+        let player_level = players[0].player_level;
+        let (rules, some_poem_data_vec) = match self.queue_map.get_mut(queue_id) {
+            Some(game_queue) => (
+                game_queue.rules,
+                game_queue
+                    .poem_table
+                    .get_random_game_data(player_level, game_queue.rules.poem_num),
+            ),
+            None => {
+                log::error!("Unknown queue {}, can not start game!", queue_id);
+                return None;
+            }
+        };
 
+        let team_vec = gamematch::assign_teams(&elo_score_vec, team_size);
+        for (player, team_id) in players.iter_mut().zip(team_vec) {
+            player.team_id = team_id;
+            player.next_opt_index = 0;
+            player.opt_end_index = rules.poem_num as i32;
+            player.next_opt_timeout_timestamp = curr_timestamp + rules.poem_mill_time + 1;
+            player.opt_start_timestamp = curr_timestamp;
+        }
+
+        if let Some(poem_data_vec) = some_poem_data_vec {
+            let game = Game::new(
+                queue_id.to_string(),
+                rules,
+                players,
+                poem_data_vec,
+                curr_timestamp,
+                is_rated,
+            );
+            let (player1, player2) = (&game.players[0], &game.players[1]);
             let gc_start_game = proto::GCStartGame {
-                game_id: game_id,
-                player1_id: player1_id,
-                player1_name: player1_name,
-                player2_id: player2_id,
-                player2_name: player2_name,
-                poem_data: poem_data_vec,
+                game_id: game.id.clone(),
+                player1_id: player1.player_id.clone(),
+                player1_name: player1.player_name.clone(),
+                player2_id: player2.player_id.clone(),
+                player2_name: player2.player_name.clone(),
+                players: game
+                    .players
+                    .iter()
+                    .map(|player| proto::GamePlayerInfo {
+                        id: player.player_id.clone(),
+                        name: player.player_name.clone(),
+                        team: player.team_id as u32,
+                    })
+                    .collect(),
+                poem_data: game.poem_data.clone(),
+                poem_num: rules.poem_num,
+                poem_mill_time: rules.poem_mill_time,
+                poem_result_wait: rules.poem_result_wait,
+                lockstep: rules.lockstep,
             };
 
             // 创建消息同步 Signal
-            if let Some(gc_start_game_json_str) =
-                proto::ProtoData::gc_to_json_string(proto::PROTO_GCSTARTGAME, gc_start_game)
-            {
-                let signal = Signal::Sync(
-                    player1.endpoint_id.clone(),
-                    player2.endpoint_id.clone(),
-                    gc_start_game_json_str,
-                );
+            let gc_start_game_json_str =
+                proto::ProtoData::gc_to_message(proto::PROTO_GCSTARTGAME, gc_start_game);
+            let signal = Signal::Sync(game.endpoint_ids(), gc_start_game_json_str);
 
-                let game = Game::new(player1, player2, curr_timestamp);
-                self.game_map.insert(game.id.clone(), game);
+            let game_id = game.id.clone();
+            self.game_map.insert(game.id.clone(), game);
 
-                let game_num = self.game_map.len();
-                if let Ok(()) = self.tx.send(RedisOpt::GameStatus(game_num as u32)) {}
+            let game_num = self.game_map.len();
+            if let Ok(()) = self.tx.send(RedisOpt::GameStatus(game_num as u32)) {}
 
-                return Some(signal);
-            } else {
-                log::error!("创建GCStartGame消息时Json序列化失败，无法进行游戏!");
-            }
+            return Some((game_id, signal));
         } else {
             log::error!("诗词数据生成失败，无法进行游戏!");
         }
@@ -472,17 +1432,19 @@ impl MatchGameController {
         return None;
     }
 
+    // 队列不存在时返回 None
     pub fn create_robot_player(
         &mut self,
+        queue_id: &str,
         competitor_player: &Player,
         curr_timestamp: i64,
-        poem_mill_time: i64,
-    ) -> Player {
-        let robot = self.robot_ctrl.get_robot(
+    ) -> Option<Player> {
+        let game_queue = self.queue_map.get_mut(queue_id)?;
+        let robot = game_queue.robot_ctrl.get_robot(
             competitor_player.player_level,
             competitor_player.player_elo_score,
             competitor_player.player_correct_rate,
-            poem_mill_time,
+            game_queue.rules.poem_mill_time,
         );
         let player = Player {
             endpoint_id: None,
@@ -490,17 +1452,28 @@ impl MatchGameController {
             player_name: robot.name.clone(),
             player_level: robot.level,
             player_elo_score: robot.elo_score,
+            player_rated_games: competitor_player.player_rated_games,
+            player_rating_deviation: competitor_player.player_rating_deviation,
+            player_rating_volatility: competitor_player.player_rating_volatility,
             player_correct_rate: robot.correct_rate,
+            team_id: 0,
             game_start_timestamp: curr_timestamp,
             next_opt_index: 0,
+            opt_end_index: 0,
             next_opt_timeout_timestamp: -1,
-            opt_bitmap: 0,
+            opt_start_timestamp: -1,
+            opt_results: Vec::new(),
+            opt_timestamps: Vec::new(),
             is_dirty: false,
             robot: Some(robot),
             game_score: 0,
+            disconnect_timestamp: None,
+            is_robot_takeover: false,
+            is_forfeit: false,
+            cheat_record: CheatRecord::default(),
         };
         log::info!("ROBOT player created: {:?}", player);
-        return player;
+        return Some(player);
     }
 
     pub fn game_count(&self) -> usize {
@@ -515,15 +1488,256 @@ pub fn create_player_from_match(match_reqeust: MatchRequest, curr_timestamp: i64
         player_name: match_reqeust.player_name,
         player_level: match_reqeust.player_level,
         player_elo_score: match_reqeust.player_elo_score,
+        player_rated_games: match_reqeust.player_rated_games,
+        player_rating_deviation: match_reqeust.player_rating_deviation,
+        player_rating_volatility: match_reqeust.player_rating_volatility,
         player_correct_rate: match_reqeust.player_correct_rate,
+        team_id: 0,
         game_start_timestamp: curr_timestamp,
         next_opt_index: 0,
+        opt_end_index: 0,
         next_opt_timeout_timestamp: -1,
-        opt_bitmap: 0,
+        opt_start_timestamp: -1,
+        opt_results: Vec::new(),
+        opt_timestamps: Vec::new(),
         is_dirty: false,
         robot: None,
         game_score: 0,
+        disconnect_timestamp: None,
+        is_robot_takeover: false,
+        is_forfeit: false,
+        cheat_record: CheatRecord::default(),
     };
     log::info!("Real Player Created: {:?}", player);
     return player;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_rules() -> GameRules {
+        GameRules {
+            poem_num: 10,
+            poem_mill_time: 10000,
+            poem_result_wait: 2500,
+            lockstep: false,
+        }
+    }
+
+    fn test_cheat_checker() -> CheatChecker {
+        let (tx, _rx) = std::sync::mpsc::channel();
+        CheatChecker::new(
+            AntiCheatConfig {
+                min_reaction_time: 300,
+                fast_reaction_time: 1000,
+                max_fast_streak: 5,
+                inhuman_reaction_score: 10.0,
+                fast_streak_score: 5.0,
+                out_of_order_score: 10.0,
+                flag_threshold: 30.0,
+                void_flagged_elo: true,
+            },
+            tx,
+        )
+    }
+
+    fn test_player(player_id: &str, curr_timestamp: i64, rules: &GameRules) -> Player {
+        let mut player = create_player_from_match(
+            MatchRequest {
+                queue_id: "classic_zh".to_string(),
+                endpoint_id: None,
+                player_id: player_id.to_string(),
+                player_name: player_id.to_string(),
+                player_level: 0,
+                player_elo_score: 1500,
+                player_rated_games: 0,
+                player_rating_deviation: rating::DEFAULT_DEVIATION,
+                player_rating_volatility: rating::DEFAULT_VOLATILITY,
+                player_correct_rate: 0.0,
+                timestamp: curr_timestamp,
+            },
+            curr_timestamp,
+        );
+        player.opt_end_index = rules.poem_num as i32;
+        player.next_opt_timeout_timestamp = curr_timestamp + rules.poem_mill_time + 1;
+        player.opt_start_timestamp = curr_timestamp;
+        return player;
+    }
+
+    fn answer(
+        player: &mut Player,
+        opt_index: u32,
+        curr_timestamp: i64,
+        rules: &GameRules,
+        cheat_checker: &mut CheatChecker,
+    ) {
+        let opt = proto::CGMatchGameOpt {
+            id: player.player_id.clone(),
+            game_id: "game".to_string(),
+            opt_index,
+            opt_result: 0,
+            opt_sign: None,
+        };
+        let mut context = OptContext {
+            rules,
+            poem_score: 1000,
+            trust_client_opt_result: true,
+            cheat_checker,
+        };
+        let result = player.on_opt(opt, curr_timestamp, None, &mut context);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn answer_after_result_screen_is_not_flagged() {
+        let rules = test_rules();
+        let mut cheat_checker = test_cheat_checker();
+        let mut player = test_player("p1", 0, &rules);
+
+        answer(&mut player, 0, 3000, &rules, &mut cheat_checker);
+        // 客户端显示完结果后 1.5s 答题
+        let shown_timestamp = 3000 + rules.poem_result_wait - CLIENT_RESULT_SLACK;
+        answer(
+            &mut player,
+            1,
+            shown_timestamp + 1500,
+            &rules,
+            &mut cheat_checker,
+        );
+
+        // 1.5s 不算快速答对，只多算 1s 时会被当成 0.5s
+        assert_eq!(player.cheat_record.fast_streak, 0);
+        assert_eq!(player.cheat_record.suspicion, 0.0);
+        assert!(!cheat_checker.is_flagged(&player.cheat_record));
+    }
+
+    #[test]
+    fn answer_right_after_shown_is_inhuman() {
+        let rules = test_rules();
+        let mut cheat_checker = test_cheat_checker();
+        let mut player = test_player("p1", 0, &rules);
+
+        answer(&mut player, 0, 3000, &rules, &mut cheat_checker);
+        let shown_timestamp = 3000 + rules.poem_result_wait - CLIENT_RESULT_SLACK;
+        answer(
+            &mut player,
+            1,
+            shown_timestamp + 100,
+            &rules,
+            &mut cheat_checker,
+        );
+
+        assert_eq!(player.cheat_record.suspicion, 10.0);
+    }
+
+    #[test]
+    fn forfeited_player_cannot_resume_or_answer() {
+        let rules = test_rules();
+        let mut cheat_checker = test_cheat_checker();
+        let mut robot_ctrl = RobotController::new("./configs/robot_info.csv");
+        let mut player1 = test_player("p1", 0, &rules);
+        player1.endpoint_id = Some("e1".to_string());
+        let mut player2 = test_player("p2", 0, &rules);
+        player2.team_id = 1;
+        let mut game = Game::new(
+            "classic_zh".to_string(),
+            rules,
+            vec![player1, player2],
+            Vec::new(),
+            0,
+            true,
+        );
+
+        game.on_disconnect("e1", 1000);
+        game.update_disconnect_status(
+            1000 + 15000 + 1,
+            15000,
+            DisconnectPolicy::Forfeit,
+            &mut robot_ctrl,
+        );
+        assert!(game.players[0].is_forfeit);
+
+        // 判负后重连失败，不会绑定到新的连接上
+        let some_message =
+            game.gc_resume_game_to_json("e2".to_string(), "p1", 17000, &mut robot_ctrl);
+        assert!(some_message.is_some());
+        assert_eq!(game.players[0].endpoint_id, None);
+
+        let opt = proto::CGMatchGameOpt {
+            id: "p1".to_string(),
+            game_id: game.id.clone(),
+            opt_index: 0,
+            opt_result: 0,
+            opt_sign: None,
+        };
+        let result = game.on_opt(opt, 17000, 1000, true, &mut cheat_checker);
+        assert_eq!(result, Err(proto::ErrorCode::Forfeited));
+        assert!(game.players[0].opt_results.is_empty());
+    }
+
+    fn poem_line(level_id: u32) -> PoemLineRecord {
+        PoemLineRecord {
+            level_id,
+            poem_id: level_id,
+            q_sign: level_id * 1000,
+            a_sign1: level_id * 1000,
+            a_sign2: 1,
+            a_sign3: 2,
+            a_sign4: 3,
+        }
+    }
+
+    fn test_robot(id: &str, next_early_opt_time: i64) -> Robot {
+        Robot {
+            id: id.to_string(),
+            name: id.to_string(),
+            level: 0,
+            elo_score: 1500,
+            correct_rate: 100.0,
+            next_early_opt_time,
+        }
+    }
+
+    #[test]
+    fn tiebreak_winner_is_earliest_answer() {
+        let rules = GameRules {
+            poem_num: 1,
+            ..test_rules()
+        };
+        let mut cheat_checker = test_cheat_checker();
+        let mut player1 = test_player("p1", 0, &rules);
+        let mut player2 = test_player("p2", 0, &rules);
+        player2.team_id = 1;
+        answer(&mut player1, 0, 3000, &rules, &mut cheat_checker);
+        answer(&mut player2, 0, 3000, &rules, &mut cheat_checker);
+        let mut game = Game::new(
+            "classic_zh".to_string(),
+            rules,
+            vec![player1, player2],
+            vec![poem_line(1)],
+            0,
+            true,
+        );
+
+        let tiebreak_config = TiebreakConfig {
+            enabled: true,
+            max_rounds: 3,
+        };
+        let tied_team_vec = game.update_end_status(&tiebreak_config).unwrap();
+        assert_eq!(tied_team_vec, vec![0, 1]);
+        game.start_tiebreak_round(&tied_team_vec, poem_line(2), 10000);
+        let timeout_timestamp = 10000 + rules.poem_mill_time;
+
+        // 两个机器人在同一帧作答，排在后面的 p2 预定的作答时间更早
+        game.players[0].robot = Some(test_robot("p1", 2000));
+        game.players[1].robot = Some(test_robot("p2", 6000));
+        game.update_robot_opt(timeout_timestamp - 1000, 1000);
+        assert!(game.players.iter().all(|player| player.is_opt_correct(1)));
+
+        game.update_tiebreak_winner();
+        assert_eq!(game.tiebreak_team, Some(1));
+        assert_eq!(game.update_end_status(&tiebreak_config), None);
+        assert_eq!(game.team_ranks(), vec![2, 1]);
+    }
+}
@@ -3,15 +3,21 @@ use message_io::node::{self, NodeEvent};
 use std::collections::HashMap;
 use std::sync::mpsc;
 use std::thread;
+mod anticheat;
+mod auth;
 mod common;
 mod config;
 mod gamematch;
 mod gameplay;
+mod matchindex;
 mod petable;
 mod poemtable;
 mod proto;
+mod rating;
 mod robot;
 mod robottable;
+mod room;
+mod session;
 mod utils;
 extern crate redis;
 use redis::Commands;
@@ -24,13 +30,26 @@ fn main() {
 
     let (tx_for_server, rx_for_game_loop) = mpsc::channel();
     let (tx_redis, rx_for_redis_handler) = mpsc::channel();
+    let (tx_redis_reply, rx_for_game_loop_redis) = mpsc::channel();
 
     let (handler, listener) = node::split();
     start_redis_handler(
         server_config.match_data_key_name.clone(),
         server_config.game_num_key_name.clone(),
         server_config.clients_num_key_name.clone(),
+        server_config.suspicion_key_name.clone(),
+        server_config
+            .match_queues
+            .iter()
+            .map(|queue_config| {
+                (
+                    queue_config.queue_id(),
+                    queue_config.rating_key_name(&server_config.rating_key_name),
+                )
+            })
+            .collect(),
         rx_for_redis_handler,
+        tx_redis_reply,
     );
     start_server(
         handler.clone(),
@@ -38,10 +57,25 @@ fn main() {
         tx_for_server.clone(),
         tx_redis.clone(),
         server_config.port,
+        HelloConfig {
+            areas: server_config
+                .match_queues
+                .iter()
+                .map(|queue_config| queue_config.area.clone())
+                .collect(),
+            min_client_version: server_config.min_client_version,
+            enable_binary_encoding: server_config.enable_binary_encoding,
+        },
     );
 
     let task = thread::spawn(move || {
-        start_game_loop(handler, tx_redis.clone(), rx_for_game_loop, &server_config);
+        start_game_loop(
+            handler,
+            tx_redis.clone(),
+            rx_for_game_loop,
+            rx_for_game_loop_redis,
+            &server_config,
+        );
     });
 
     // --------------------------- Debug ------------------------
@@ -64,10 +98,12 @@ fn main() {
     //             correct_rate: correct_rate,
     //         };
     //         if let Some(json_str) =
-    //             proto::ProtoData::gc_to_json_string(proto::PROTO_CGSTARTMATCH, cg_start_match)
+    //             proto::ProtoData::gc_to_message(proto::PROTO_CGSTARTMATCH, cg_start_match)
     //         {
     //             log::info!("发送匹配请求");
-    //             if let Ok(_) = tx_for_server_clone.send((endpoint_id, json_str)) {
+    //             if let Ok(_) = tx_for_server_clone
+    //                 .send(common::ServerEvent::Message(endpoint_id, json_str))
+    //             {
     //             } else {
     //                 log::error!("发送匹配请求失败");
     //             }
@@ -82,9 +118,10 @@ fn main() {
 fn start_server(
     server_handler: message_io::node::NodeHandler<common::Signal>,
     listener: message_io::node::NodeListener<common::Signal>,
-    tx: std::sync::mpsc::Sender<(std::string::String, std::string::String)>,
+    tx: std::sync::mpsc::Sender<common::ServerEvent>,
     tx_redis: std::sync::mpsc::Sender<common::RedisOpt>,
     port: u32,
+    hello_config: HelloConfig,
 ) {
     thread::spawn(move || {
         // 等2秒后再启动监听
@@ -100,7 +137,7 @@ fn start_server(
                     NetEvent::Connected(_, _) => unreachable!(),
                     NetEvent::Accepted(_endpoint, _listener) => {
                         let endpoint_id = _endpoint.resource_id().to_string();
-                        clients.insert(endpoint_id, _endpoint);
+                        clients.insert(endpoint_id, common::Connection::new(_endpoint));
                         log::info!(
                             "Client connected: {:?}, TotalConnection: {}",
                             _endpoint.resource_id(),
@@ -118,24 +155,90 @@ fn start_server(
                             String::from_utf8_lossy(data)
                         );
                         // handler.network().send(endpoint, data);
-                        if let Ok(json_str) = std::str::from_utf8(data) {
-                            let endpoint_id = endpoint.resource_id().to_string();
-                            if let Ok(()) = tx.send((endpoint_id, json_str.to_string())) {
-                            } else {
-                                log::error!("channel send error!");
+                        let endpoint_id = endpoint.resource_id().to_string();
+                        let is_binary_frame = proto::ProtoData::is_binary_frame(data);
+                        let json_result = if is_binary_frame && !hello_config.enable_binary_encoding
+                        {
+                            // 服务器没有开启二进制编码，不切换该连接的编码
+                            log::warn!(
+                                "Binary frame from {:?} rejected, binary encoding disabled",
+                                endpoint.resource_id()
+                            );
+                            Err(proto::ErrorCode::BadFrame)
+                        } else if is_binary_frame {
+                            // 客户端发来二进制帧，之后回复该连接的消息也使用二进制帧
+                            if let Some(connection) = clients.get_mut(&endpoint_id) {
+                                connection.encoding = proto::Encoding::Binary;
                             }
+                            proto::ProtoData::binary_to_json_string(data)
+                        } else if let Ok(json_str) = std::str::from_utf8(data) {
+                            Ok(json_str.to_string())
                         } else {
                             log::error!("ERROR!, Received Binary data -> json str failed!");
+                            Err(proto::ErrorCode::BadEnvelope)
+                        };
+
+                        // 握手被拒绝的连接，不再处理它的消息
+                        let json_result = match clients.get(&endpoint_id) {
+                            Some(connection) if connection.is_refused => {
+                                Err(proto::ErrorCode::ClientRefused)
+                            }
+                            _ => json_result,
+                        };
+
+                        match json_result {
+                            Ok(json_str) => {
+                                // 握手消息在网络线程处理，结果记录在连接上
+                                if let Ok((proto::PROTO_CGHELLO, proto_json_str)) =
+                                    proto::ProtoData::cg_to_proto_json_str(json_str.clone())
+                                {
+                                    if let Some(connection) = clients.get_mut(&endpoint_id) {
+                                        handle_hello(
+                                            &server_handler,
+                                            connection,
+                                            proto_json_str,
+                                            &hello_config,
+                                        );
+                                    }
+                                } else if let Ok(()) =
+                                    tx.send(common::ServerEvent::Message(endpoint_id, json_str))
+                                {
+                                } else {
+                                    log::error!("channel send error!");
+                                }
+                            }
+                            Err(error_code) => {
+                                let mut proto_json_str =
+                                    proto::ProtoData::gc_error_to_message(0, error_code);
+                                if let Some(connection) = clients.get(&endpoint_id) {
+                                    send_to_client(
+                                        &server_handler,
+                                        connection,
+                                        &mut proto_json_str,
+                                    );
+                                }
+                            }
                         }
                     }
                     NetEvent::Disconnected(_endpoint) => {
                         let endpoint_id = _endpoint.resource_id().to_string();
-                        clients.remove(&endpoint_id);
+                        let client_version = if let Some(connection) = clients.remove(&endpoint_id)
+                        {
+                            connection.client_version
+                        } else {
+                            None
+                        };
                         log::info!(
-                            "Client disconnected: {:?}, TotalConnection: {}",
+                            "Client disconnected: {:?}, version: {:?}, TotalConnection: {}",
                             endpoint_id,
+                            client_version,
                             clients.len()
                         );
+                        // 通知游戏逻辑，玩家可能在游戏中，需要等待重连
+                        if let Ok(()) = tx.send(common::ServerEvent::Disconnected(endpoint_id)) {
+                        } else {
+                            log::error!("channel send error!");
+                        }
                         if let Ok(()) =
                             tx_redis.send(common::RedisOpt::ServerStatus(clients.len() as u32))
                         {
@@ -143,29 +246,17 @@ fn start_server(
                     }
                 },
                 NodeEvent::Signal(signal) => match signal {
-                    common::Signal::Send(endpoint_id, json_str) => {
+                    common::Signal::Send(endpoint_id, mut gc_message) => {
                         log::info!("Send Msg to client: {} --------\n", endpoint_id);
-                        if let Some(client_endpoint) = clients.get(&endpoint_id) {
-                            let data = json_str.as_bytes();
-                            server_handler.network().send(*client_endpoint, data);
+                        if let Some(connection) = clients.get(&endpoint_id) {
+                            send_to_client(&server_handler, connection, &mut gc_message);
                         }
                     }
-                    common::Signal::Sync(endpoint_id1, endpoint_id2, json_str) => {
-                        log::info!(
-                            "Sync to client: {:?} - {:?} --------\n",
-                            endpoint_id1,
-                            endpoint_id2
-                        );
-                        let data = json_str.as_bytes();
-                        if let Some(endpoint_id1) = endpoint_id1 {
-                            if let Some(client_endpoint) = clients.get(&endpoint_id1) {
-                                server_handler.network().send(*client_endpoint, data);
-                            }
-                        }
-
-                        if let Some(endpoint_id2) = endpoint_id2 {
-                            if let Some(client_endpoint) = clients.get(&endpoint_id2) {
-                                server_handler.network().send(*client_endpoint, data);
+                    common::Signal::Sync(endpoint_id_vec, mut gc_message) => {
+                        log::info!("Sync to client: {:?} --------\n", endpoint_id_vec);
+                        for endpoint_id in endpoint_id_vec.iter() {
+                            if let Some(connection) = clients.get(endpoint_id) {
+                                send_to_client(&server_handler, connection, &mut gc_message);
                             }
                         }
                     }
@@ -175,21 +266,132 @@ fn start_server(
     });
 }
 
+struct HelloConfig {
+    areas: Vec<String>, // 所有匹配队列的地区
+    min_client_version: u32,
+    enable_binary_encoding: bool,
+}
+
+// 处理握手，记录客户端版本，选择双方都支持的编码
+fn handle_hello(
+    server_handler: &message_io::node::NodeHandler<common::Signal>,
+    connection: &mut common::Connection,
+    proto_json_str: String,
+    hello_config: &HelloConfig,
+) {
+    let hello_info = match proto::ProtoData::deserialize_proto::<proto::CGHello>(proto_json_str) {
+        Ok(hello_info) => hello_info,
+        Err(error_code) => {
+            log::error!("ERROR!, Received CGHello, but deserialize failed");
+            let mut proto_json_str =
+                proto::ProtoData::gc_error_to_message(proto::PROTO_CGHELLO, error_code);
+            send_to_client(server_handler, connection, &mut proto_json_str);
+            return;
+        }
+    };
+
+    log::info!(
+        "Client {:?} Hello: {:?}",
+        connection.endpoint.resource_id(),
+        hello_info
+    );
+    connection.client_version = Some(hello_info.version);
+    connection.client_area = Some(hello_info.area.clone());
+
+    let code = if hello_info.version < hello_config.min_client_version {
+        -1
+    } else if !hello_config.areas.contains(&hello_info.area) {
+        -2
+    } else {
+        0
+    };
+
+    let encoding = if code == 0
+        && hello_config.enable_binary_encoding
+        && hello_info
+            .encodings
+            .iter()
+            .any(|name| proto::Encoding::from_name(name) == Some(proto::Encoding::Binary))
+    {
+        proto::Encoding::Binary
+    } else {
+        proto::Encoding::Json
+    };
+
+    if code != 0 {
+        log::warn!(
+            "Client {:?} refused, version: {}, area: {}",
+            connection.endpoint.resource_id(),
+            hello_info.version,
+            hello_info.area
+        );
+        connection.is_refused = true;
+    }
+
+    let mut proto_json_str = proto::ProtoData::gc_to_message(
+        proto::PROTO_GCHELLO,
+        proto::GCHello {
+            code,
+            encoding: encoding.name().to_string(),
+            min_client_version: hello_config.min_client_version,
+        },
+    );
+    send_to_client(server_handler, connection, &mut proto_json_str);
+    // 回复使用握手时的编码发送，之后才切换到协商的编码
+    connection.encoding = encoding;
+}
+
+// 按连接协商的编码发送消息
+fn send_to_client(
+    server_handler: &message_io::node::NodeHandler<common::Signal>,
+    connection: &common::Connection,
+    gc_message: &mut proto::GCMessage,
+) {
+    match gc_message.bytes(connection.encoding) {
+        Some(bytes) => {
+            server_handler.network().send(connection.endpoint, bytes);
+        }
+        None => log::error!(
+            "Encode GC proto {} as {} failed!",
+            gc_message.proto_id(),
+            connection.encoding.name()
+        ),
+    }
+}
+
 fn start_game_loop(
     handler: message_io::node::NodeHandler<common::Signal>,
     tx_to_redis_handler: std::sync::mpsc::Sender<common::RedisOpt>,
-    rx_from_server: std::sync::mpsc::Receiver<(std::string::String, std::string::String)>,
+    rx_from_server: std::sync::mpsc::Receiver<common::ServerEvent>,
+    rx_from_redis: std::sync::mpsc::Receiver<common::RedisReply>,
     config: &config::ServerConfig,
 ) {
     log::info!("Game Loop Started!");
-    // 当前在游戏中的玩家，开始匹配的时间
-    let mut gaming_player_map: HashMap<String, i64> = HashMap::new();
-    let mut match_controller = gamematch::MatchController::new();
+    // 玩家当前的状态：匹配中、游戏中
+    let mut session_controller = session::SessionController::new(config.auth_secret.clone());
+    let mut match_controller = gamematch::MatchController::new(
+        create_rating_systems(config),
+        &config.match_queues,
+        &config.match_schedule,
+        &config.rematch,
+        config.match_status_interval,
+    );
     let mut match_game_controller = gameplay::MatchGameController::new(
-        tx_to_redis_handler,
-        config.poem_mill_time,
-        config.poem_score,
+        tx_to_redis_handler.clone(),
+        &config.match_queues,
+        create_rating_systems(config),
+        gameplay::GameSettings {
+            poem_mill_time: config.poem_mill_time,
+            poem_score: config.poem_score,
+            disconnect_grace_time: config.disconnect_grace_time,
+            disconnect_policy: config.disconnect_policy,
+            trust_client_opt_result: config.trust_client_opt_result,
+            anti_cheat_config: config.anti_cheat.clone(),
+            margin_config: config.margin_of_victory.clone(),
+            tiebreak_config: config.tiebreak.clone(),
+        },
     );
+    let mut room_controller = room::RoomController::new(config.room.clone());
 
     let mut last_update_timestamp: i64 = utils::get_timestamp_millis();
     let mut sum_frame = 0;
@@ -200,43 +402,164 @@ fn start_game_loop(
     // game server logic loop
     loop {
         let curr_timestamp = utils::get_timestamp_millis();
-        if let Ok((endpoint_id, json_str)) = rx_from_server.try_recv() {
+        if let Ok(redis_reply) = rx_from_redis.try_recv() {
+            match redis_reply {
+                common::RedisReply::PlayerRating(player_id, some_rating) => {
+                    log::info!("Player {} rating loaded: {:?}", player_id, some_rating);
+                    if !room_controller.on_rating_loaded(&player_id, some_rating.clone()) {
+                        match_controller.on_rating_loaded(&player_id, some_rating);
+                    }
+                }
+                common::RedisReply::PlayerRatingFailed(player_id) => {
+                    // 读不到分数就不能开始匹配，回复匹配失败
+                    if let Some(match_request) = match_controller.cancel_match(&player_id) {
+                        session_controller.set_idle(&player_id);
+                        if let Some(endpoint_id) = match_request.endpoint_id {
+                            let proto_json_str = proto::ProtoData::gc_to_message(
+                                proto::PROTO_GCSTARTMATCH,
+                                proto::GCStartMatch { code: -2 },
+                            );
+                            handler
+                                .signals()
+                                .send(common::Signal::Send(endpoint_id, proto_json_str));
+                        }
+                    } else if let Some((room_code, match_request_vec)) =
+                        room_controller.leave_room(&player_id)
+                    {
+                        close_room(
+                            &handler,
+                            &mut session_controller,
+                            &room_code,
+                            match_request_vec,
+                            room::ROOM_CLOSE_RATING_FAILED,
+                        );
+                    }
+                }
+            }
+        }
+
+        if let Ok(server_event) = rx_from_server.try_recv() {
+            let (endpoint_id, json_str) = match server_event {
+                common::ServerEvent::Message(endpoint_id, json_str) => (endpoint_id, json_str),
+                common::ServerEvent::Disconnected(endpoint_id) => {
+                    log::info!("Received Disconnected From Server: {}", endpoint_id);
+                    if let Some(match_request) =
+                        match_controller.cancel_match_by_endpoint(&endpoint_id)
+                    {
+                        session_controller.set_idle(&match_request.player_id);
+                    }
+                    if let Some((room_code, match_request_vec)) =
+                        room_controller.leave_room_by_endpoint(&endpoint_id)
+                    {
+                        close_room(
+                            &handler,
+                            &mut session_controller,
+                            &room_code,
+                            match_request_vec,
+                            room::ROOM_CLOSE_DISCONNECTED,
+                        );
+                    }
+                    match_game_controller.on_disconnect(&endpoint_id, curr_timestamp);
+                    session_controller.unbind_endpoint(&endpoint_id);
+                    continue;
+                }
+            };
             log::info!("Received Channel Info From Server: {}", endpoint_id);
-            if let Some((proto_id, proto_json_str)) =
-                proto::ProtoData::cg_to_proto_json_str(json_str)
-            {
-                match proto_id {
+            match proto::ProtoData::cg_to_proto_json_str(json_str) {
+                Ok((proto_id, proto_json_str)) => match proto_id {
                     proto::PROTO_CGSTARTMATCH => {
                         log::info!("Handle Client Proto CGStartMatch");
-                        if let Some(match_info) = proto::ProtoData::deserialize_proto::<
-                            proto::CGStartMatch,
-                        >(proto_json_str)
-                        {
-                            // if !gaming_player_map.contains_key(&match_info.id) {
-                            let start_match_timestamp = curr_timestamp;
+                        match proto::ProtoData::deserialize_proto::<proto::CGStartMatch>(
+                            proto_json_str,
+                        )
+                        .and_then(|match_info| {
+                            session_controller
+                                .authenticate(
+                                    &endpoint_id,
+                                    &match_info.id,
+                                    &match_info.token,
+                                    curr_timestamp,
+                                )
+                                .map(|_| match_info)
+                        }) {
+                            Ok(match_info) => {
+                                let some_queue_id = match_controller.find_queue(
+                                    &match_info.mode,
+                                    default_area(&match_info.area, &config.area),
+                                );
+                                let code = match (
+                                    session_controller.get_state(&match_info.id),
+                                    some_queue_id,
+                                ) {
+                                    (Some(session::PlayerState::Gaming(game_id)), _) => {
+                                        // 玩家当前已经在游戏中，直接回复匹配失败
+                                        log::warn!(
+                                            "Player {} is in game {}, match failed!",
+                                            match_info.id,
+                                            game_id
+                                        );
+                                        -1
+                                    }
+                                    (Some(session::PlayerState::InRoom(room_code)), _) => {
+                                        // 在好友房间里等待，需要先退出房间
+                                        log::warn!(
+                                            "Player {} is in room {}, match failed!",
+                                            match_info.id,
+                                            room_code
+                                        );
+                                        -3
+                                    }
+                                    (_, None) => {
+                                        log::warn!(
+                                            "Player {} request unknown queue, mode: {}, area: {}",
+                                            match_info.id,
+                                            match_info.mode,
+                                            match_info.area
+                                        );
+                                        -4
+                                    }
+                                    (some_state, Some(queue_id)) => {
+                                        // 已经在匹配中的，用新的请求替换掉旧的（比如重连后再次请求匹配）
+                                        if let Some(session::PlayerState::Matching(
+                                            start_timestamp,
+                                        )) = some_state
+                                        {
+                                            log::warn!(
+                                            "Player {} is matching since {}, replace the old request",
+                                            match_info.id,
+                                            start_timestamp
+                                        );
+                                            match_controller.cancel_match(&match_info.id);
+                                        }
 
-                            let match_request = gamematch::MatchRequest {
-                                endpoint_id: if endpoint_id.is_empty() {
-                                    None
-                                } else {
-                                    Some(endpoint_id.clone())
-                                },
-                                player_id: match_info.id.clone(),
-                                player_name: match_info.name.clone(),
-                                player_level: match_info.level,
-                                player_elo_score: match_info.elo_score,
-                                player_correct_rate: match_info.correct_rate,
-                                timestamp: curr_timestamp,
-                            };
-
-                            gaming_player_map.insert(match_info.id, start_match_timestamp);
-                            match_controller.add_match(match_request);
-                            // 回复消息，匹配中 CGStartMatch
-
-                            if let Some(proto_json_str) = proto::ProtoData::gc_to_json_string(
-                                proto::PROTO_GCSTARTMATCH,
-                                proto::GCStartMatch { code: 0 },
-                            ) {
+                                        // 客户端上报的分数不再使用，以服务器保存的为准
+                                        let match_request = new_match_request(
+                                            queue_id.clone(),
+                                            &endpoint_id,
+                                            &match_info.id,
+                                            &match_info.name,
+                                            match_info.correct_rate,
+                                            config.initial_elo_score,
+                                            curr_timestamp,
+                                        );
+
+                                        session_controller
+                                            .set_matching(&match_info.id, curr_timestamp);
+                                        match_controller.add_pending_match(match_request);
+                                        load_player_rating(
+                                            &tx_to_redis_handler,
+                                            &queue_id,
+                                            &match_info.id,
+                                        );
+                                        0
+                                    }
+                                };
+
+                                // 回复消息，匹配中 CGStartMatch
+                                let proto_json_str = proto::ProtoData::gc_to_message(
+                                    proto::PROTO_GCSTARTMATCH,
+                                    proto::GCStartMatch { code },
+                                );
                                 if !endpoint_id.is_empty() {
                                     log::info!("Response CGStartMatch -> Client: {}", endpoint_id);
                                     handler
@@ -244,43 +567,369 @@ fn start_game_loop(
                                         .send(common::Signal::Send(endpoint_id, proto_json_str));
                                 }
                             }
-                            // } else {
-                            //     // 玩家当前已经在匹配或游戏中，暂时不让进了，直接回复匹配失败
-                            //     log::warn!("Client {} is in game, match failed!", endpoint_id,);
-                            //     if let Some(proto_json_str) = proto::ProtoData::gc_to_json_string(
-                            //         proto::PROTO_GCSTARTMATCH,
-                            //         proto::GCStartMatch { code: -1 },
-                            //     ) {
-                            //         log::info!(
-                            //             "Response CGStartMatch Failed -> Client: {}",
-                            //             endpoint_id
-                            //         );
-
-                            //         if !endpoint_id.is_empty() {
-                            //             handler.signals().send(common::Signal::Send(
-                            //                 endpoint_id,
-                            //                 proto_json_str,
-                            //             ));
-                            //         }
-                            //     }
-                            // }
+                            Err(error_code) => {
+                                log::error!(
+                                    "ERROR!, Received CGStartMatch, but handle failed: {:?}",
+                                    error_code
+                                );
+                                send_error(&handler, endpoint_id, proto_id, error_code);
+                            }
                         }
                     }
                     proto::PROTO_CGMATCHGAMEOPT => {
                         log::info!("Handle Client Proto OPT");
-                        if let Some(opt_info) = proto::ProtoData::deserialize_proto::<
-                            proto::CGMatchGameOpt,
-                        >(proto_json_str)
-                        {
-                            match_game_controller.on_opt(opt_info, curr_timestamp);
-                        } else {
-                            log::error!("ERROR!, Received Game OPT, but deserialize failed");
+                        match proto::ProtoData::deserialize_proto::<proto::CGMatchGameOpt>(
+                            proto_json_str,
+                        )
+                        .and_then(|opt_info| {
+                            session_controller
+                                .check_identity(&endpoint_id, &opt_info.id)
+                                .map(|_| opt_info)
+                        }) {
+                            Ok(opt_info) => {
+                                if let Err(error_code) =
+                                    match_game_controller.on_opt(opt_info, curr_timestamp)
+                                {
+                                    log::error!("ERROR!, Game OPT failed: {:?}", error_code);
+                                    send_error(&handler, endpoint_id, proto_id, error_code);
+                                }
+                            }
+                            Err(error_code) => {
+                                log::error!(
+                                    "ERROR!, Received Game OPT, but handle failed: {:?}",
+                                    error_code
+                                );
+                                send_error(&handler, endpoint_id, proto_id, error_code);
+                            }
                         }
                     }
-                    _ => {}
+                    proto::PROTO_CGCANCELMATCH => {
+                        log::info!("Handle Client Proto CGCancelMatch");
+                        match proto::ProtoData::deserialize_proto::<proto::CGCancelMatch>(
+                            proto_json_str,
+                        )
+                        .and_then(|cancel_info| {
+                            session_controller
+                                .check_identity(&endpoint_id, &cancel_info.id)
+                                .map(|_| cancel_info)
+                        }) {
+                            Ok(cancel_info) => {
+                                let code =
+                                    if match_controller.cancel_match(&cancel_info.id).is_some() {
+                                        session_controller.set_idle(&cancel_info.id);
+                                        0
+                                    } else {
+                                        -1
+                                    };
+
+                                let proto_json_str = proto::ProtoData::gc_to_message(
+                                    proto::PROTO_GCCANCELMATCH,
+                                    proto::GCCancelMatch { code },
+                                );
+                                if !endpoint_id.is_empty() {
+                                    log::info!("Response CGCancelMatch -> Client: {}", endpoint_id);
+                                    handler
+                                        .signals()
+                                        .send(common::Signal::Send(endpoint_id, proto_json_str));
+                                }
+                            }
+                            Err(error_code) => {
+                                log::error!(
+                                    "ERROR!, Received CGCancelMatch, but handle failed: {:?}",
+                                    error_code
+                                );
+                                send_error(&handler, endpoint_id, proto_id, error_code);
+                            }
+                        }
+                    }
+                    proto::PROTO_CGRESUMEGAME => {
+                        log::info!("Handle Client Proto CGResumeGame");
+                        match proto::ProtoData::deserialize_proto::<proto::CGResumeGame>(
+                            proto_json_str,
+                        )
+                        .and_then(|resume_info| {
+                            session_controller
+                                .authenticate(
+                                    &endpoint_id,
+                                    &resume_info.id,
+                                    &resume_info.token,
+                                    curr_timestamp,
+                                )
+                                .map(|_| resume_info)
+                        }) {
+                            Ok(resume_info) => {
+                                let proto_json_str = match_game_controller.resume_game(
+                                    endpoint_id.clone(),
+                                    resume_info,
+                                    curr_timestamp,
+                                );
+                                log::info!("Response CGResumeGame -> Client: {}", endpoint_id);
+                                handler
+                                    .signals()
+                                    .send(common::Signal::Send(endpoint_id, proto_json_str));
+                            }
+                            Err(error_code) => {
+                                log::error!(
+                                    "ERROR!, Received CGResumeGame, but handle failed: {:?}",
+                                    error_code
+                                );
+                                send_error(&handler, endpoint_id, proto_id, error_code);
+                            }
+                        }
+                    }
+                    proto::PROTO_CGCREATEROOM => {
+                        log::info!("Handle Client Proto CGCreateRoom");
+                        match proto::ProtoData::deserialize_proto::<proto::CGCreateRoom>(
+                            proto_json_str,
+                        )
+                        .and_then(|room_info| {
+                            session_controller
+                                .authenticate(
+                                    &endpoint_id,
+                                    &room_info.id,
+                                    &room_info.token,
+                                    curr_timestamp,
+                                )
+                                .map(|_| room_info)
+                        }) {
+                            Ok(room_info) => {
+                                let gc_create_room = if let Some(state) =
+                                    session_controller.get_state(&room_info.id)
+                                {
+                                    // 匹配中、游戏中或已经在房间里，不能创建房间
+                                    log::warn!(
+                                        "Player {} is busy: {:?}, create room failed!",
+                                        room_info.id,
+                                        state
+                                    );
+                                    proto::GCCreateRoom {
+                                        code: -1,
+                                        room_code: String::new(),
+                                        expire_time: 0,
+                                    }
+                                } else if let Some(queue_id) = match_controller.find_queue(
+                                    &room_info.mode,
+                                    default_area(&room_info.area, &config.area),
+                                ) {
+                                    let match_request = new_match_request(
+                                        queue_id.clone(),
+                                        &endpoint_id,
+                                        &room_info.id,
+                                        &room_info.name,
+                                        room_info.correct_rate,
+                                        config.initial_elo_score,
+                                        curr_timestamp,
+                                    );
+                                    match room_controller.create_room(
+                                        match_request,
+                                        room_info.rated,
+                                        curr_timestamp,
+                                    ) {
+                                        Ok(room_code) => {
+                                            session_controller
+                                                .set_in_room(&room_info.id, &room_code);
+                                            load_player_rating(
+                                                &tx_to_redis_handler,
+                                                &queue_id,
+                                                &room_info.id,
+                                            );
+                                            proto::GCCreateRoom {
+                                                code: 0,
+                                                room_code,
+                                                expire_time: room_controller.expire_time(),
+                                            }
+                                        }
+                                        Err(code) => proto::GCCreateRoom {
+                                            code,
+                                            room_code: String::new(),
+                                            expire_time: 0,
+                                        },
+                                    }
+                                } else {
+                                    log::warn!(
+                                        "Player {} create room in unknown queue, mode: {}, area: {}",
+                                        room_info.id,
+                                        room_info.mode,
+                                        room_info.area
+                                    );
+                                    proto::GCCreateRoom {
+                                        code: -2,
+                                        room_code: String::new(),
+                                        expire_time: 0,
+                                    }
+                                };
+
+                                let proto_json_str = proto::ProtoData::gc_to_message(
+                                    proto::PROTO_GCCREATEROOM,
+                                    gc_create_room,
+                                );
+                                if !endpoint_id.is_empty() {
+                                    log::info!("Response CGCreateRoom -> Client: {}", endpoint_id);
+                                    handler
+                                        .signals()
+                                        .send(common::Signal::Send(endpoint_id, proto_json_str));
+                                }
+                            }
+                            Err(error_code) => {
+                                log::error!(
+                                    "ERROR!, Received CGCreateRoom, but handle failed: {:?}",
+                                    error_code
+                                );
+                                send_error(&handler, endpoint_id, proto_id, error_code);
+                            }
+                        }
+                    }
+                    proto::PROTO_CGJOINROOM => {
+                        log::info!("Handle Client Proto CGJoinRoom");
+                        match proto::ProtoData::deserialize_proto::<proto::CGJoinRoom>(
+                            proto_json_str,
+                        )
+                        .and_then(|join_info| {
+                            session_controller
+                                .authenticate(
+                                    &endpoint_id,
+                                    &join_info.id,
+                                    &join_info.token,
+                                    curr_timestamp,
+                                )
+                                .map(|_| join_info)
+                        }) {
+                            Ok(join_info) => {
+                                let code = if let Some(state) =
+                                    session_controller.get_state(&join_info.id)
+                                {
+                                    log::warn!(
+                                        "Player {} is busy: {:?}, join room failed!",
+                                        join_info.id,
+                                        state
+                                    );
+                                    -4
+                                } else {
+                                    // 加入房间后使用房主所在的队列
+                                    let match_request = new_match_request(
+                                        String::new(),
+                                        &endpoint_id,
+                                        &join_info.id,
+                                        &join_info.name,
+                                        join_info.correct_rate,
+                                        config.initial_elo_score,
+                                        curr_timestamp,
+                                    );
+                                    match room_controller
+                                        .join_room(&join_info.room_code, match_request)
+                                    {
+                                        Ok(queue_id) => {
+                                            session_controller
+                                                .set_in_room(&join_info.id, &join_info.room_code);
+                                            load_player_rating(
+                                                &tx_to_redis_handler,
+                                                &queue_id,
+                                                &join_info.id,
+                                            );
+                                            0
+                                        }
+                                        Err(code) => {
+                                            log::warn!(
+                                                "Player {} join room {} failed: {}",
+                                                join_info.id,
+                                                join_info.room_code,
+                                                code
+                                            );
+                                            code
+                                        }
+                                    }
+                                };
+
+                                let proto_json_str = proto::ProtoData::gc_to_message(
+                                    proto::PROTO_GCJOINROOM,
+                                    proto::GCJoinRoom {
+                                        code,
+                                        room_code: join_info.room_code,
+                                    },
+                                );
+                                if !endpoint_id.is_empty() {
+                                    log::info!("Response CGJoinRoom -> Client: {}", endpoint_id);
+                                    handler
+                                        .signals()
+                                        .send(common::Signal::Send(endpoint_id, proto_json_str));
+                                }
+                            }
+                            Err(error_code) => {
+                                log::error!(
+                                    "ERROR!, Received CGJoinRoom, but handle failed: {:?}",
+                                    error_code
+                                );
+                                send_error(&handler, endpoint_id, proto_id, error_code);
+                            }
+                        }
+                    }
+                    proto::PROTO_CGCANCELROOM => {
+                        log::info!("Handle Client Proto CGCancelRoom");
+                        match proto::ProtoData::deserialize_proto::<proto::CGCancelRoom>(
+                            proto_json_str,
+                        )
+                        .and_then(|cancel_info| {
+                            session_controller
+                                .check_identity(&endpoint_id, &cancel_info.id)
+                                .map(|_| cancel_info)
+                        }) {
+                            Ok(cancel_info) => {
+                                let code = if let Some((room_code, match_request_vec)) =
+                                    room_controller.leave_room(&cancel_info.id)
+                                {
+                                    // 通知房间里的其他玩家，取消的玩家只回复 GCCancelRoom
+                                    session_controller.set_idle(&cancel_info.id);
+                                    close_room(
+                                        &handler,
+                                        &mut session_controller,
+                                        &room_code,
+                                        match_request_vec
+                                            .into_iter()
+                                            .filter(|match_request| {
+                                                match_request.player_id != cancel_info.id
+                                            })
+                                            .collect(),
+                                        room::ROOM_CLOSE_CANCELED,
+                                    );
+                                    0
+                                } else {
+                                    -1
+                                };
+
+                                let proto_json_str = proto::ProtoData::gc_to_message(
+                                    proto::PROTO_GCCANCELROOM,
+                                    proto::GCCancelRoom { code },
+                                );
+                                if !endpoint_id.is_empty() {
+                                    log::info!("Response CGCancelRoom -> Client: {}", endpoint_id);
+                                    handler
+                                        .signals()
+                                        .send(common::Signal::Send(endpoint_id, proto_json_str));
+                                }
+                            }
+                            Err(error_code) => {
+                                log::error!(
+                                    "ERROR!, Received CGCancelRoom, but handle failed: {:?}",
+                                    error_code
+                                );
+                                send_error(&handler, endpoint_id, proto_id, error_code);
+                            }
+                        }
+                    }
+                    _ => {
+                        log::error!("ERROR!, Received unknown proto: {}", proto_id);
+                        send_error(
+                            &handler,
+                            endpoint_id,
+                            proto_id,
+                            proto::ErrorCode::UnknownProto,
+                        );
+                    }
+                },
+                Err(error_code) => {
+                    log::error!("反序列化 ProtoData->Value 失败");
+                    send_error(&handler, endpoint_id, 0, error_code);
                 }
-            } else {
-                log::error!("反序列化 ProtoData->Value 失败");
             }
         }
 
@@ -308,44 +957,262 @@ fn start_game_loop(
                     handler.signals().send(signal);
                 }
             }
+            // 已经结束的游戏中的玩家，回到空闲状态
+            for player_id in match_game_controller.ended_players().iter() {
+                session_controller.set_idle(player_id);
+            }
+
+            // 告诉匹配中的玩家匹配进度
+            for signal in match_controller.update_match_status(curr_timestamp) {
+                handler.signals().send(signal);
+            }
 
-            if let Some((some_match_request1, some_match_request2)) =
-                match_controller.update_matches(curr_timestamp)
-            {
-                if let Some(match_request1) = some_match_request1 {
-                    let game_player1 =
-                        gameplay::create_player_from_match(match_request1, curr_timestamp);
-                    let game_player2 = if let Some(match_request2) = some_match_request2 {
-                        gameplay::create_player_from_match(match_request2, curr_timestamp)
-                    } else {
-                        match_game_controller.create_robot_player(
-                            &game_player1,
+            // 好友房间：两个玩家的分数都读取完成后开始游戏，超时的房间关闭
+            let (ready_room_vec, expired_room_vec) = room_controller.update_rooms(curr_timestamp);
+            for ready_room in ready_room_vec {
+                let queue_id = ready_room.owner_request.queue_id.clone();
+                start_game(
+                    &mut StartGameContext {
+                        handler: &handler,
+                        session_controller: &mut session_controller,
+                        match_game_controller: &mut match_game_controller,
+                    },
+                    &queue_id,
+                    vec![
+                        gameplay::create_player_from_match(
+                            ready_room.owner_request,
                             curr_timestamp,
-                            config.poem_mill_time,
-                        )
-                    };
+                        ),
+                        gameplay::create_player_from_match(
+                            ready_room.guest_request,
+                            curr_timestamp,
+                        ),
+                    ],
+                    1,
+                    curr_timestamp,
+                    ready_room.is_rated,
+                );
+            }
+            for (room_code, match_request_vec) in expired_room_vec {
+                close_room(
+                    &handler,
+                    &mut session_controller,
+                    &room_code,
+                    match_request_vec,
+                    room::ROOM_CLOSE_EXPIRED,
+                );
+            }
 
-                    if let Some(start_game_signal) = match_game_controller.start_new_game(
-                        game_player1,
-                        game_player2,
+            // 匹配成功的玩家组，真实玩家不够时用机器人补齐
+            for match_group in match_controller.update_matches(curr_timestamp) {
+                let queue_id = match match_group.match_requests.first() {
+                    Some(match_request) => match_request.queue_id.clone(),
+                    None => continue,
+                };
+                let mut game_player_vec: Vec<gameplay::Player> = match_group
+                    .match_requests
+                    .into_iter()
+                    .map(|match_request| {
+                        gameplay::create_player_from_match(match_request, curr_timestamp)
+                    })
+                    .collect();
+                for _ in 0..match_group.robot_num {
+                    if let Some(robot_player) = match_game_controller.create_robot_player(
+                        &queue_id,
+                        &game_player_vec[0],
                         curr_timestamp,
                     ) {
-                        handler.signals().send(start_game_signal);
+                        game_player_vec.push(robot_player);
                     }
-                } else {
-                    log::error!("逻辑错误，匹配返回Some时第一个玩家不可能为None");
                 }
+                start_game(
+                    &mut StartGameContext {
+                        handler: &handler,
+                        session_controller: &mut session_controller,
+                        match_game_controller: &mut match_game_controller,
+                    },
+                    &queue_id,
+                    game_player_vec,
+                    match_group.team_size,
+                    curr_timestamp,
+                    true,
+                );
             }
         }
     }
 }
 
+// 客户端请求匹配或进入房间时，分数和等级先用默认值，之后以 Redis 中保存的为准
+fn new_match_request(
+    queue_id: String,
+    endpoint_id: &str,
+    player_id: &str,
+    player_name: &str,
+    correct_rate: f64,
+    initial_elo_score: i32,
+    curr_timestamp: i64,
+) -> gamematch::MatchRequest {
+    gamematch::MatchRequest {
+        queue_id,
+        endpoint_id: if endpoint_id.is_empty() {
+            None
+        } else {
+            Some(endpoint_id.to_string())
+        },
+        player_id: player_id.to_string(),
+        player_name: player_name.to_string(),
+        player_level: 0,
+        player_elo_score: initial_elo_score,
+        player_rated_games: 0,
+        player_rating_deviation: rating::DEFAULT_DEVIATION,
+        player_rating_volatility: rating::DEFAULT_VOLATILITY,
+        player_correct_rate: correct_rate,
+        timestamp: curr_timestamp,
+    }
+}
+
+// 客户端没有指定地区时使用服务器的默认地区
+fn default_area<'a>(area: &'a str, default: &'a str) -> &'a str {
+    if area.is_empty() {
+        default
+    } else {
+        area
+    }
+}
+
+// 每个队列使用自己的分数系统
+fn create_rating_systems(
+    config: &config::ServerConfig,
+) -> HashMap<String, Box<dyn rating::RatingSystem>> {
+    return config
+        .match_queues
+        .iter()
+        .map(|queue_config| {
+            (
+                queue_config.queue_id(),
+                queue_config.create_rating_system(
+                    config.rating_system,
+                    config.elo_k_factor,
+                    &config.glicko2,
+                    &config.rating_rules,
+                ),
+            )
+        })
+        .collect();
+}
+
+fn load_player_rating(
+    tx_to_redis_handler: &mpsc::Sender<common::RedisOpt>,
+    queue_id: &str,
+    player_id: &str,
+) {
+    if let Ok(()) = tx_to_redis_handler.send(common::RedisOpt::LoadPlayerRating(
+        queue_id.to_string(),
+        player_id.to_string(),
+    )) {
+    } else {
+        log::error!("Send load rating to Redis failed!");
+    }
+}
+
+// 开始游戏时需要同时更新的消息发送、玩家状态和游戏
+struct StartGameContext<'a> {
+    handler: &'a message_io::node::NodeHandler<common::Signal>,
+    session_controller: &'a mut session::SessionController,
+    match_game_controller: &'a mut gameplay::MatchGameController,
+}
+
+// 开始游戏，游戏创建成功玩家进入游戏中状态，否则回到空闲状态
+fn start_game(
+    context: &mut StartGameContext,
+    queue_id: &str,
+    game_player_vec: Vec<gameplay::Player>,
+    team_size: usize,
+    curr_timestamp: i64,
+    is_rated: bool,
+) {
+    let player_id_vec: Vec<String> = game_player_vec
+        .iter()
+        .filter(|player| !player.is_robot())
+        .map(|player| player.player_id().to_string())
+        .collect();
+
+    if let Some((game_id, start_game_signal)) = context.match_game_controller.start_new_game(
+        queue_id,
+        game_player_vec,
+        team_size,
+        curr_timestamp,
+        is_rated,
+    ) {
+        for player_id in player_id_vec.iter() {
+            context.session_controller.set_gaming(player_id, &game_id);
+        }
+        context.handler.signals().send(start_game_signal);
+    } else {
+        for player_id in player_id_vec.iter() {
+            context.session_controller.set_idle(player_id);
+        }
+    }
+}
+
+// 房间关闭，通知房间里的玩家，玩家回到空闲状态
+fn close_room(
+    handler: &message_io::node::NodeHandler<common::Signal>,
+    session_controller: &mut session::SessionController,
+    room_code: &str,
+    match_request_vec: Vec<gamematch::MatchRequest>,
+    reason: i32,
+) {
+    for match_request in match_request_vec {
+        session_controller.set_idle(&match_request.player_id);
+        if let Some(endpoint_id) = match_request.endpoint_id {
+            let proto_json_str = proto::ProtoData::gc_to_message(
+                proto::PROTO_GCROOMCLOSED,
+                proto::GCRoomClosed {
+                    room_code: room_code.to_string(),
+                    reason,
+                },
+            );
+            log::info!("Send GCRoomClosed {} -> Client: {}", reason, endpoint_id);
+            handler
+                .signals()
+                .send(common::Signal::Send(endpoint_id, proto_json_str));
+        }
+    }
+}
+
+// 将错误码回复给发送消息的客户端
+fn send_error(
+    handler: &message_io::node::NodeHandler<common::Signal>,
+    endpoint_id: String,
+    proto_id: u64,
+    error_code: proto::ErrorCode,
+) {
+    if endpoint_id.is_empty() {
+        return;
+    }
+
+    let proto_json_str = proto::ProtoData::gc_error_to_message(proto_id, error_code);
+    log::info!(
+        "Response GCError {:?} for proto {} -> Client: {}",
+        error_code,
+        proto_id,
+        endpoint_id
+    );
+    handler
+        .signals()
+        .send(common::Signal::Send(endpoint_id, proto_json_str));
+}
+
 // lang, player_id, player_level
 fn start_redis_handler(
     match_data_key_name: String,
     game_num_key_name: String,
     clients_num_key_name: String,
+    suspicion_key_name: String,
+    rating_key_map: HashMap<String, String>, // 队列ID - 保存分数的 key
     rx: std::sync::mpsc::Receiver<common::RedisOpt>,
+    tx_reply: std::sync::mpsc::Sender<common::RedisReply>,
 ) {
     let client = redis::Client::open("redis://127.0.0.1:6379").unwrap();
     let mut conn = client.get_connection().unwrap();
@@ -378,6 +1245,82 @@ fn start_redis_handler(
                     common::RedisOpt::ServerStatus(client_num) => {
                         if let Ok(()) = conn.set(&clients_num_key_name, client_num) {}
                     }
+                    common::RedisOpt::LoadPlayerRating(queue_id, player_id) => {
+                        let rating_key_name = match rating_key_map.get(&queue_id) {
+                            Some(rating_key_name) => rating_key_name,
+                            None => {
+                                log::error!("玩家 {} 的队列 {} 不存在", player_id, queue_id);
+                                if let Ok(()) =
+                                    tx_reply.send(common::RedisReply::PlayerRatingFailed(player_id))
+                                {
+                                } else {
+                                    log::error!("channel send error!");
+                                }
+                                continue;
+                            }
+                        };
+                        let redis_reply = match conn
+                            .hget::<&str, &str, Option<String>>(rating_key_name, &player_id)
+                        {
+                            Ok(Some(json_str)) => {
+                                if let Ok(rating) =
+                                    serde_json::from_str::<rating::PlayerRating>(&json_str)
+                                {
+                                    common::RedisReply::PlayerRating(player_id, Some(rating))
+                                } else {
+                                    log::error!(
+                                        "玩家 {} 分数数据解析失败: {}",
+                                        player_id,
+                                        json_str
+                                    );
+                                    common::RedisReply::PlayerRatingFailed(player_id)
+                                }
+                            }
+                            Ok(None) => common::RedisReply::PlayerRating(player_id, None),
+                            Err(err) => {
+                                log::error!("!!!!!!!! 玩家 {} 分数读取失败: {:?}", player_id, err);
+                                common::RedisReply::PlayerRatingFailed(player_id)
+                            }
+                        };
+                        if let Ok(()) = tx_reply.send(redis_reply) {
+                        } else {
+                            log::error!("channel send error!");
+                        }
+                    }
+                    common::RedisOpt::SavePlayerRating(queue_id, player_id, rating) => {
+                        let rating_key_name = match rating_key_map.get(&queue_id) {
+                            Some(rating_key_name) => rating_key_name,
+                            None => {
+                                log::error!("玩家 {} 的队列 {} 不存在", player_id, queue_id);
+                                continue;
+                            }
+                        };
+                        if let Ok(json_str) = serde_json::to_string(&rating) {
+                            if let Ok(_result) = conn.hset::<&str, &str, String, usize>(
+                                rating_key_name,
+                                &player_id,
+                                json_str,
+                            ) {
+                                log::info!("玩家 {}, 分数 {:?} 保存成功!", player_id, rating);
+                            } else {
+                                log::info!(
+                                    "!!!!!!!! 玩家 {}, 分数 {:?} 保存失败!",
+                                    player_id,
+                                    rating
+                                );
+                            }
+                        }
+                    }
+                    common::RedisOpt::PlayerSuspicion(player_id, score) => {
+                        if let Ok(_result) = conn.zincr::<&str, &str, f64, f64>(
+                            &suspicion_key_name,
+                            &player_id,
+                            score,
+                        ) {
+                        } else {
+                            log::info!("!!!!!!!! 玩家 {}, 可疑分 {} 添加失败!", player_id, score);
+                        }
+                    }
                 }
             }
         }
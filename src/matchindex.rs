@@ -0,0 +1,267 @@
+use crate::gamematch::MatchRequest;
+use std::collections::btree_set;
+use std::collections::{BTreeSet, HashMap};
+use std::iter::{Peekable, Rev};
+use std::ops::Bound::{Excluded, Unbounded};
+
+// 匹配队列的索引
+// 按 elo 分数排序，查找分差最小的对手只需要看左右相邻的玩家
+// 按开始匹配的时间排序，等待最久的玩家先匹配
+pub struct MatchIndex {
+    request_map: HashMap<String, MatchRequest>,
+    elo_set: BTreeSet<(i32, String)>,
+    wait_set: BTreeSet<(i64, String)>,
+}
+
+impl MatchIndex {
+    pub fn new() -> Self {
+        Self {
+            request_map: HashMap::new(),
+            elo_set: BTreeSet::new(),
+            wait_set: BTreeSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.request_map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.request_map.is_empty()
+    }
+
+    // 同一个玩家只保留最新的请求，返回被替换掉的旧请求
+    pub fn insert(&mut self, match_request: MatchRequest) -> Option<MatchRequest> {
+        let some_old_request = self.remove(&match_request.player_id);
+        self.elo_set.insert((
+            match_request.player_elo_score,
+            match_request.player_id.clone(),
+        ));
+        self.wait_set
+            .insert((match_request.timestamp, match_request.player_id.clone()));
+        self.request_map
+            .insert(match_request.player_id.clone(), match_request);
+        return some_old_request;
+    }
+
+    pub fn remove(&mut self, player_id: &str) -> Option<MatchRequest> {
+        let match_request = self.request_map.remove(player_id)?;
+        self.elo_set.remove(&(
+            match_request.player_elo_score,
+            match_request.player_id.clone(),
+        ));
+        self.wait_set
+            .remove(&(match_request.timestamp, match_request.player_id.clone()));
+        return Some(match_request);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MatchRequest> {
+        self.request_map.values()
+    }
+
+    pub fn find_by_endpoint(&self, endpoint_id: &str) -> Option<&MatchRequest> {
+        self.request_map
+            .values()
+            .find(|match_req| match_req.endpoint_id.as_deref() == Some(endpoint_id))
+    }
+
+    // 按等待时间从长到短排列，some_after 为 (开始匹配的时间, 玩家ID)，只返回排在它之后的玩家
+    pub fn iter_by_wait(
+        &self,
+        some_after: Option<&(i64, String)>,
+    ) -> impl Iterator<Item = &MatchRequest> {
+        let range = match some_after {
+            Some(after) => self.wait_set.range((Excluded(after), Unbounded)),
+            None => self.wait_set.range(..),
+        };
+        range.filter_map(move |(_, player_id)| self.request_map.get(player_id))
+    }
+
+    // 其他玩家，按与该玩家的分差从小到大排列
+    pub fn neighbors(&self, player_id: &str) -> Neighbors<'_> {
+        let (elo_score, below, above) = match self.request_map.get(player_id) {
+            Some(match_req) => {
+                let key = (match_req.player_elo_score, match_req.player_id.clone());
+                (
+                    match_req.player_elo_score,
+                    self.elo_set.range(..key.clone()),
+                    self.elo_set.range((Excluded(key), Unbounded)),
+                )
+            }
+            // 不在队列中的玩家没有对手，由 is_queued 控制不返回任何玩家
+            None => (0, self.elo_set.range(..), self.elo_set.range(..)),
+        };
+        let is_queued = self.request_map.contains_key(player_id);
+        Neighbors {
+            request_map: &self.request_map,
+            elo_score,
+            below: below.rev().peekable(),
+            above: above.peekable(),
+            is_queued,
+        }
+    }
+}
+
+pub struct Neighbors<'a> {
+    request_map: &'a HashMap<String, MatchRequest>,
+    elo_score: i32,
+    below: Peekable<Rev<btree_set::Range<'a, (i32, String)>>>,
+    above: Peekable<btree_set::Range<'a, (i32, String)>>,
+    is_queued: bool,
+}
+
+impl<'a> Iterator for Neighbors<'a> {
+    type Item = &'a MatchRequest;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.is_queued {
+            return None;
+        }
+        let use_below = match (self.below.peek(), self.above.peek()) {
+            (Some((below_score, _)), Some((above_score, _))) => {
+                self.elo_score.abs_diff(*below_score) <= self.elo_score.abs_diff(*above_score)
+            }
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return None,
+        };
+        let some_entry = if use_below {
+            self.below.next()
+        } else {
+            self.above.next()
+        };
+        return some_entry.and_then(|(_, player_id)| self.request_map.get(player_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rating;
+
+    fn request(player_id: &str, elo_score: i32, timestamp: i64) -> MatchRequest {
+        MatchRequest {
+            queue_id: "classic_zh".to_string(),
+            endpoint_id: None,
+            player_id: player_id.to_string(),
+            player_name: player_id.to_string(),
+            player_level: 0,
+            player_elo_score: elo_score,
+            player_rated_games: 0,
+            player_rating_deviation: rating::DEFAULT_DEVIATION,
+            player_rating_volatility: rating::DEFAULT_VOLATILITY,
+            player_correct_rate: 0.0,
+            timestamp,
+        }
+    }
+
+    fn neighbor_ids(match_index: &MatchIndex, player_id: &str) -> Vec<String> {
+        match_index
+            .neighbors(player_id)
+            .map(|match_req| match_req.player_id.clone())
+            .collect()
+    }
+
+    fn assert_consistent(match_index: &MatchIndex) {
+        assert_eq!(match_index.elo_set.len(), match_index.len());
+        assert_eq!(match_index.wait_set.len(), match_index.len());
+        for match_req in match_index.iter() {
+            let player_id = match_req.player_id.clone();
+            assert!(match_index
+                .elo_set
+                .contains(&(match_req.player_elo_score, player_id.clone())));
+            assert!(match_index
+                .wait_set
+                .contains(&(match_req.timestamp, player_id)));
+        }
+    }
+
+    #[test]
+    fn neighbors_by_elo_diff() {
+        let mut match_index = MatchIndex::new();
+        for (player_id, elo_score) in [
+            ("a", 1000),
+            ("b", 1090),
+            ("c", 1100),
+            ("d", 1130),
+            ("e", 1200),
+        ] {
+            match_index.insert(request(player_id, elo_score, 0));
+        }
+
+        // 分差相同时先取分数低的一边
+        assert_eq!(neighbor_ids(&match_index, "c"), ["b", "d", "a", "e"]);
+        assert_eq!(neighbor_ids(&match_index, "a"), ["b", "c", "d", "e"]);
+        assert_eq!(neighbor_ids(&match_index, "e"), ["d", "c", "b", "a"]);
+        assert!(neighbor_ids(&match_index, "unknown").is_empty());
+    }
+
+    #[test]
+    fn neighbors_with_same_elo() {
+        let mut match_index = MatchIndex::new();
+        for player_id in ["a", "b", "c"] {
+            match_index.insert(request(player_id, 1000, 0));
+        }
+
+        let mut neighbor_id_vec = neighbor_ids(&match_index, "b");
+        neighbor_id_vec.sort();
+        assert_eq!(neighbor_id_vec, ["a", "c"]);
+    }
+
+    #[test]
+    fn insert_replaces_old_request() {
+        let mut match_index = MatchIndex::new();
+        match_index.insert(request("a", 1000, 0));
+        match_index.insert(request("b", 1100, 10));
+
+        let some_old_request = match_index.insert(request("a", 1200, 20));
+        assert_eq!(some_old_request.map(|old| old.player_elo_score), Some(1000));
+        assert_eq!(match_index.len(), 2);
+        assert_consistent(&match_index);
+        assert_eq!(neighbor_ids(&match_index, "b"), ["a"]);
+
+        let wait_id_vec: Vec<&str> = match_index
+            .iter_by_wait(None)
+            .map(|match_req| match_req.player_id.as_str())
+            .collect();
+        assert_eq!(wait_id_vec, ["b", "a"]);
+    }
+
+    #[test]
+    fn remove_keeps_sets_consistent() {
+        let mut match_index = MatchIndex::new();
+        for (index, player_id) in ["a", "b", "c", "d"].iter().enumerate() {
+            match_index.insert(request(player_id, 1000 + index as i32 * 10, index as i64));
+        }
+
+        assert!(match_index.remove("b").is_some());
+        assert!(match_index.remove("b").is_none());
+        assert_consistent(&match_index);
+        assert_eq!(neighbor_ids(&match_index, "a"), ["c", "d"]);
+
+        for player_id in ["a", "c", "d"] {
+            match_index.remove(player_id);
+        }
+        assert!(match_index.is_empty());
+        assert_consistent(&match_index);
+    }
+
+    #[test]
+    fn iter_by_wait_after_cursor() {
+        let mut match_index = MatchIndex::new();
+        match_index.insert(request("a", 1000, 30));
+        match_index.insert(request("b", 1000, 10));
+        match_index.insert(request("c", 1000, 20));
+
+        let wait_ids = |some_after: Option<&(i64, String)>| -> Vec<String> {
+            match_index
+                .iter_by_wait(some_after)
+                .map(|match_req| match_req.player_id.clone())
+                .collect()
+        };
+        assert_eq!(wait_ids(None), ["b", "c", "a"]);
+        assert_eq!(wait_ids(Some(&(10, "b".to_string()))), ["c", "a"]);
+        // 游标上的玩家已经被移除，仍然从它之后继续
+        assert_eq!(wait_ids(Some(&(15, "x".to_string()))), ["c", "a"]);
+    }
+}
@@ -11,6 +11,8 @@ pub struct PERecord {
 
 pub struct PETable {
     pub pe_vec: Vec<PERecord>,
+    // 表中最后一组的分差跨度，超出表的分差按这个跨度继续分组
+    overflow_group_width: u32,
 }
 
 impl PETable {
@@ -23,15 +25,26 @@ impl PETable {
             pe_vec.push(record);
         }
 
-        Self { pe_vec }
-    }
-
-    pub fn get_ea_eb(&self, elo1_score: u32, elo2_score: u32) -> (f64, f64, u32) {
-        let diff_score = if elo1_score > elo2_score {
-            elo1_score - elo2_score
+        let overflow_group_width = if let Some(last_record) = pe_vec.last() {
+            let group_dmin = pe_vec
+                .iter()
+                .filter(|record| record.group == last_record.group)
+                .map(|record| record.dmin)
+                .min()
+                .unwrap_or(last_record.dmin);
+            (last_record.dmax - group_dmin + 1).max(1)
         } else {
-            elo2_score - elo1_score
+            1
         };
+
+        Self {
+            pe_vec,
+            overflow_group_width,
+        }
+    }
+
+    pub fn get_ea_eb(&self, elo1_score: i32, elo2_score: i32) -> (f64, f64, u32) {
+        let diff_score = elo1_score.abs_diff(elo2_score);
         let mut ea = -1.0f64;
         let mut eb = -1.0f64;
         let mut group = 0;
@@ -44,11 +57,17 @@ impl PETable {
             }
         }
 
-        // 如果没有在表中找到，则说明超出范围了
+        // 如果没有在表中找到，则说明超出范围了，按 elo 公式计算期望胜率，分组继续往后排
         if ea < 0.0 && eb < 0.0 {
-            ea = 1.0;
-            eb = 0.0;
-            group = 9;
+            let (last_dmax, last_ea, last_group) = match self.pe_vec.last() {
+                Some(record) => (record.dmax, record.ea, record.group),
+                None => (0, 0.5, 0),
+            };
+            ea = (1.0 / (1.0 + 10f64.powf(-(diff_score as f64) / 400.0))).max(last_ea);
+            eb = 1.0 - ea;
+            group = last_group
+                + 1
+                + diff_score.saturating_sub(last_dmax + 1) / self.overflow_group_width;
         }
 
         if elo1_score > elo2_score {
@@ -62,8 +62,8 @@ pub struct PoemTable {
 }
 
 impl PoemTable {
-    pub fn new() -> Self {
-        let file = std::fs::File::open("./configs/poem.csv").unwrap();
+    pub fn new(path: &str) -> Self {
+        let file = std::fs::File::open(path).unwrap();
         let mut rdr = csv::Reader::from_reader(file);
 
         let mut level_map: HashMap<u32, PoemRecord> = HashMap::new();
@@ -102,6 +102,26 @@ impl PoemTable {
         }
     }
 
+    // 加赛题目：从本局还没有出过的关卡中随机选一句，不和已经出过的诗重复
+    pub fn get_random_line_excluding(
+        &mut self,
+        used_poem_data: &[PoemLineRecord],
+    ) -> Option<PoemLineRecord> {
+        let mut rng = rand::thread_rng();
+        let level_id = self
+            .level_vec_map
+            .get(&0)?
+            .iter()
+            .filter(|level_id| {
+                !used_poem_data
+                    .iter()
+                    .any(|line_record| line_record.level_id == **level_id)
+            })
+            .copied()
+            .choose(&mut rng)?;
+        return self.level_map.get_mut(&level_id)?.get_random_line_record();
+    }
+
     pub fn get_random_game_data(&mut self, level: u32, count: u32) -> Option<Vec<PoemLineRecord>> {
         //Option<String> {
         // let key = match level {
@@ -120,9 +140,8 @@ impl PoemTable {
         if let Some(ref mut level_id_vec) = self.level_vec_map.get_mut(&key) {
             level_id_vec.shuffle(&mut rng);
 
-            for i in 0..count {
-                let level_id = level_id_vec[i as usize];
-                if let Some(poem_record) = self.level_map.get_mut(&level_id) {
+            for level_id in level_id_vec.iter().take(count as usize) {
+                if let Some(poem_record) = self.level_map.get_mut(level_id) {
                     if let Some(random_line_record) = poem_record.get_random_line_record() {
                         selected_poem_record.push(random_line_record);
                     } else {
@@ -147,3 +166,47 @@ impl PoemTable {
         return None;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 除了 except_level_id 以外，每首诗各出过一句
+    fn used_poem_data(poem_table: &mut PoemTable, except_level_id: u32) -> Vec<PoemLineRecord> {
+        let level_id_vec = poem_table.level_vec_map[&0].clone();
+        return level_id_vec
+            .iter()
+            .filter(|level_id| **level_id != except_level_id)
+            .filter_map(|level_id| {
+                poem_table
+                    .level_map
+                    .get_mut(level_id)?
+                    .get_random_line_record()
+            })
+            .collect();
+    }
+
+    #[test]
+    fn random_line_excludes_used_poems() {
+        let mut poem_table = PoemTable::new("./configs/poem.csv");
+        let mut used_poem_data = used_poem_data(&mut poem_table, 5);
+        assert_eq!(used_poem_data.len(), poem_table.count as usize - 1);
+
+        for _ in 0..10 {
+            let line_record = poem_table
+                .get_random_line_excluding(&used_poem_data)
+                .unwrap();
+            assert_eq!(line_record.level_id, 5);
+        }
+
+        // 所有的诗都出过了，没有可以加赛的题目
+        used_poem_data.push(
+            poem_table
+                .get_random_line_excluding(&used_poem_data)
+                .unwrap(),
+        );
+        assert!(poem_table
+            .get_random_line_excluding(&used_poem_data)
+            .is_none());
+    }
+}
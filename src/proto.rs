@@ -8,18 +8,129 @@ pub const PROTO_CGMATCHGAMEOPT: u64 = 1002;
 pub const PROTO_GCSTARTGAME: u64 = 2002;
 pub const PROTO_GCUPDATEGAME: u64 = 2003;
 pub const PROTO_GCENDGAME: u64 = 2004;
+pub const PROTO_CGRESUMEGAME: u64 = 1003;
+pub const PROTO_GCRESUMEGAME: u64 = 2005;
+pub const PROTO_CGCANCELMATCH: u64 = 1004;
+pub const PROTO_GCCANCELMATCH: u64 = 2006;
+pub const PROTO_GCERROR: u64 = 2007;
+pub const PROTO_CGHELLO: u64 = 1005;
+pub const PROTO_GCHELLO: u64 = 2008;
+pub const PROTO_GCMATCHSTATUS: u64 = 2009;
+pub const PROTO_CGCREATEROOM: u64 = 1006;
+pub const PROTO_GCCREATEROOM: u64 = 2010;
+pub const PROTO_CGJOINROOM: u64 = 1007;
+pub const PROTO_GCJOINROOM: u64 = 2011;
+pub const PROTO_CGCANCELROOM: u64 = 1008;
+pub const PROTO_GCCANCELROOM: u64 = 2012;
+pub const PROTO_GCROOMCLOSED: u64 = 2013;
+pub const PROTO_GCTIEBREAK: u64 = 2014;
+pub const PROTO_GCNEXTQUESTION: u64 = 2015;
+
+// 客户端消息处理失败的错误码，通过 GCError 回复给客户端
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    BadEnvelope = 1,   // 外层 ProtoData 解析失败
+    BadBase64 = 2,     // proto_json_str 不是合法的 base64
+    BadJson = 3,       // 协议 json 解析失败
+    UnknownProto = 4,  // 未知的 proto_id
+    UnknownGame = 5,   // game_id 对应的游戏不存在或已结束
+    NotYourGame = 6,   // 玩家不在这局游戏中
+    StaleOptIndex = 7, // 操作的索引不是当前要答的题
+    BadFrame = 8,      // 二进制帧长度或 MessagePack 数据不合法
+    ClientRefused = 9, // 握手时客户端版本或地区不兼容，连接上的消息不再处理
+    Unauthorized = 10, // 令牌校验失败，或玩家ID与连接上绑定的不一致
+    Forfeited = 11,    // 玩家已经断线判负，不能继续答题
+}
+
+// 连接上使用的消息编码
+// Json: {"proto_id": 1001, "proto_json_str": "base64(json)"}
+// Binary: [u32 长度][u64 proto_id][MessagePack 协议数据]，长度不含自身 4 字节，大端序
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    Binary,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Self::Json),
+            "binary" => Some(Self::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Binary => "binary",
+        }
+    }
+}
+
+const BINARY_FRAME_HEADER_LEN: usize = 4 + 8;
+
+// 发给客户端的消息，第一次发给某种编码的连接时才从协议编码成该格式，发给多个玩家时不再重复编码
+pub struct GCMessage {
+    proto_id: u64,
+    proto: Box<dyn GCProto>,
+    json_str: Option<String>,
+    binary_frame: Option<Vec<u8>>,
+}
+
+impl GCMessage {
+    pub fn proto_id(&self) -> u64 {
+        self.proto_id
+    }
+
+    // 编码失败返回 None
+    pub fn bytes(&mut self, encoding: Encoding) -> Option<&[u8]> {
+        match encoding {
+            Encoding::Json => {
+                if self.json_str.is_none() {
+                    self.json_str =
+                        ProtoData::gc_to_json_string(self.proto_id, self.proto.as_ref());
+                }
+                return self.json_str.as_ref().map(|json_str| json_str.as_bytes());
+            }
+            Encoding::Binary => {
+                if self.binary_frame.is_none() {
+                    self.binary_frame = ProtoData::gc_to_binary(self.proto_id, self.proto.as_ref());
+                }
+                return self.binary_frame.as_deref();
+            }
+        }
+    }
+}
 
 pub trait GCProtoBase64 {
     fn to_base64_json_str(&self) -> Option<String>;
 }
 
+// 发给客户端的协议，两种编码都从协议本身生成
+pub trait GCProto: GCProtoBase64 + Send {
+    fn to_msgpack(&self) -> Option<Vec<u8>>;
+}
+
+impl<T: GCProtoBase64 + Serialize + Send> GCProto for T {
+    fn to_msgpack(&self) -> Option<Vec<u8>> {
+        rmp_serde::to_vec_named(self).ok()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CGStartMatch {
     pub id: String,        // 玩家ID
     pub name: String,      // 玩家昵称
-    pub level: u32,        // 胜利次数
-    pub elo_score: u32,    // elo 分值
+    pub level: u32,        // 胜利次数，仅兼容老客户端，服务器以 Redis 中保存的为准
+    pub elo_score: i32,    // elo 分值，同上
     pub correct_rate: f64, // 正确率
+    #[serde(default)]
+    pub token: String, // 登录服务器签发的会话令牌
+    #[serde(default)]
+    pub mode: String, // 玩法，为空时使用默认队列的玩法
+    #[serde(default)]
+    pub area: String, // 地区，为空时使用服务器配置的默认地区
 }
 
 // Debug Code
@@ -34,6 +145,7 @@ impl GCProtoBase64 for CGStartMatch {
     }
 }
 
+// 0 匹配中，-1 玩家已经在游戏中，-2 服务器读取玩家分数失败，-3 玩家在好友房间中，-4 没有对应的匹配队列
 #[derive(Serialize)]
 pub struct GCStartMatch {
     pub code: i32,
@@ -50,6 +162,161 @@ impl GCProtoBase64 for GCStartMatch {
     }
 }
 
+// 匹配中定时发给玩家的匹配进度
+#[derive(Serialize)]
+pub struct GCMatchStatus {
+    pub waited_time: i64,    // 已经等待的时间，ms
+    pub search_group: i32,   // 当前接受的最大分差组，-1 表示任何玩家都可以匹配
+    pub queue_size: u32,     // 匹配队列中的玩家数量
+    pub estimated_time: i64, // 根据最近的匹配估算的剩余等待时间，ms
+}
+
+impl GCProtoBase64 for GCMatchStatus {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCMatchStatus: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CGCancelMatch {
+    pub id: String, // 玩家ID
+}
+
+// 0 取消成功，-1 玩家不在匹配队列中（可能已经匹配成功）
+#[derive(Serialize)]
+pub struct GCCancelMatch {
+    pub code: i32,
+}
+
+impl GCProtoBase64 for GCCancelMatch {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCCancelMatch: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
+// 创建好友对战房间
+#[derive(Deserialize, Debug)]
+pub struct CGCreateRoom {
+    pub id: String,        // 玩家ID
+    pub name: String,      // 玩家昵称
+    pub correct_rate: f64, // 正确率
+    #[serde(default)]
+    pub rated: bool, // 是否改变分数和等级，服务器配置不允许时忽略
+    #[serde(default)]
+    pub token: String, // 会话令牌
+    #[serde(default)]
+    pub mode: String, // 玩法，加入的玩家使用房主的玩法和地区
+    #[serde(default)]
+    pub area: String,
+}
+
+// 0 创建成功，-1 玩家已经在匹配、游戏或其他房间中，-2 没有对应的匹配队列，-3 没有可用的房间号
+#[derive(Serialize)]
+pub struct GCCreateRoom {
+    pub code: i32,
+    pub room_code: String, // 分享给好友的房间号
+    pub expire_time: i64,  // ms, 超过该时间没有开始游戏，房间自动关闭
+}
+
+impl GCProtoBase64 for GCCreateRoom {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCCreateRoom: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
+// 用房间号加入好友的房间
+#[derive(Deserialize, Debug)]
+pub struct CGJoinRoom {
+    pub id: String,        // 玩家ID
+    pub name: String,      // 玩家昵称
+    pub correct_rate: f64, // 正确率
+    pub room_code: String, // 房间号
+    #[serde(default)]
+    pub token: String, // 会话令牌
+}
+
+// 0 加入成功，等待游戏开始，-1 房间不存在，-2 房间已满，-3 不能加入自己的房间，-4 玩家已经在匹配、游戏或其他房间中
+#[derive(Serialize)]
+pub struct GCJoinRoom {
+    pub code: i32,
+    pub room_code: String,
+}
+
+impl GCProtoBase64 for GCJoinRoom {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCJoinRoom: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CGCancelRoom {
+    pub id: String, // 玩家ID
+}
+
+// 0 取消成功，房间关闭，-1 玩家不在房间中（可能已经开始游戏）
+#[derive(Serialize)]
+pub struct GCCancelRoom {
+    pub code: i32,
+}
+
+impl GCProtoBase64 for GCCancelRoom {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCCancelRoom: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
+// 房间被关闭，通知房间里的玩家
+#[derive(Serialize)]
+pub struct GCRoomClosed {
+    pub room_code: String,
+    pub reason: i32, // 1 超时，2 有玩家取消，3 有玩家断线，4 读取玩家分数失败
+}
+
+impl GCProtoBase64 for GCRoomClosed {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCRoomClosed: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
+// 游戏中的一个玩家
+#[derive(Serialize, Debug)]
+pub struct GamePlayerInfo {
+    pub id: String,
+    pub name: String,
+    pub team: u32, // 所在的队伍，从 0 开始，不组队时每个玩家单独一队
+}
+
+// player1、player2 兼容只支持1v1的老客户端，为 players 中的前两个玩家
 #[derive(Serialize)]
 pub struct GCStartGame {
     pub game_id: String,
@@ -57,7 +324,12 @@ pub struct GCStartGame {
     pub player1_name: String,
     pub player2_id: String,
     pub player2_name: String,
+    pub players: Vec<GamePlayerInfo>, // 本局所有玩家，多人对局时有 3 - 8 个
     pub poem_data: Vec<PoemLineRecord>,
+    pub poem_num: u32,         // 本局的题目数，由玩法决定
+    pub poem_mill_time: i64,   // ms, 每首诗的答题时间
+    pub poem_result_wait: i64, // ms, 服务器在答完一首诗后等待的时间
+    pub lockstep: bool,        // 同步答题，每首诗等 GCNextQuestion 再开始作答
 }
 
 impl GCProtoBase64 for GCStartGame {
@@ -76,7 +348,20 @@ pub struct CGMatchGameOpt {
     pub id: String,      // 玩家ID
     pub game_id: String, // 游戏ID
     pub opt_index: u32,  // 操作了哪个索引
-    pub opt_result: u32, // 操作的结果，0对，1错
+    #[serde(default)]
+    pub opt_result: u32, // 操作的结果，0对，1错。老版本客户端上报，仅在兼容模式下使用
+    #[serde(default)]
+    pub opt_sign: Option<u32>, // 玩家选择的答案 a_sign，由服务器判断对错
+}
+
+// 一个玩家的答题进度
+#[derive(Serialize, Debug)]
+pub struct GamePlayerProgress {
+    pub id: String,
+    pub name: String,
+    pub team: u32,
+    pub next_opt_index: i32,
+    pub opt_results: Vec<u32>, // 按题目顺序每道题的结果，0对，1错
 }
 
 #[derive(Serialize)]
@@ -85,11 +370,12 @@ pub struct GCUpdateGame {
     pub player1_id: String,
     pub player1_name: String,
     pub player1_next_opt_index: i32,
-    pub player1_opt_bitmap: u32,
+    pub player1_opt_bitmap: u32, // 老客户端使用，只有前 32 题，新客户端使用 players 中的 opt_results
     pub player2_id: String,
     pub player2_name: String,
     pub player2_next_opt_index: i32,
     pub player2_opt_bitmap: u32,
+    pub players: Vec<GamePlayerProgress>,
 }
 
 impl GCProtoBase64 for GCUpdateGame {
@@ -103,21 +389,51 @@ impl GCProtoBase64 for GCUpdateGame {
     }
 }
 
+// 一个玩家的结算结果
+#[derive(Serialize, Debug)]
+pub struct GamePlayerResult {
+    pub id: String,
+    pub name: String,
+    pub team: u32,
+    pub opt_results: Vec<u32>,
+    pub game_score: u32,
+    pub new_elo_score: i32,
+    pub new_level: u32,
+    pub actual_score: f64, // 多人对局为和每个对手的实际得分的平均值，组队时和队伍相同
+    pub rank: u32, // 名次，从 1 开始，得分相同的名次相同，断线判负的排在最后，组队时为队伍名次
+    pub is_forfeit: bool,
+}
+
+#[derive(Serialize)]
+pub struct GameTeamResult {
+    pub team: u32,
+    pub game_score: u32, // 队员得分之和
+    pub actual_score: f64,
+    pub rank: u32, // 整队都断线判负的排在最后
+}
+
 #[derive(Serialize)]
 pub struct GCEndGame {
     pub game_id: String,
     pub player1_id: String,
     pub player1_name: String,
-    pub player1_opt_bitmap: u32,
+    pub player1_opt_bitmap: u32, // 老客户端使用，只有前 32 题，新客户端使用 players 中的 opt_results
     pub player1_game_score: u32,
-    pub player1_new_elo_score: u32,
+    pub player1_new_elo_score: i32,
     pub player1_new_level: u32,
+    pub player1_actual_score: f64, // 计算分数时使用的实际得分，开启胜负差距时不只是 1、0、0.5
     pub player2_id: String,
     pub player2_name: String,
     pub player2_opt_bitmap: u32,
     pub player2_game_score: u32,
-    pub player2_new_elo_score: u32,
+    pub player2_new_elo_score: i32,
     pub player2_new_level: u32,
+    pub player2_actual_score: f64,
+    pub players: Vec<GamePlayerResult>,
+    pub teams: Vec<GameTeamResult>, // 不组队时每个玩家单独一队
+    pub end_type: i32, // 0 正常结束，1 玩家1断线判负，2 玩家2断线判负，3 都断线了，4 多人对局中有玩家断线判负
+    pub tiebreak_rounds: u32, // 加赛的轮数，0 没有加赛
+    pub tiebreak_team: i32, // 加赛获胜的队伍，-1 没有加赛或加赛后仍是平局
 }
 
 impl GCProtoBase64 for GCEndGame {
@@ -131,6 +447,138 @@ impl GCProtoBase64 for GCEndGame {
     }
 }
 
+// 第一名得分相同，开始一轮加赛。player_ids 中的玩家用 CGMatchGameOpt 回答 opt_index 这首诗
+// 第一个答对的玩家所在的队伍获胜，都没有答对时开始下一轮，结果在 GCEndGame 中
+#[derive(Serialize)]
+pub struct GCTiebreak {
+    pub game_id: String,
+    pub round: u32, // 第几轮加赛，从 1 开始
+    pub opt_index: u32,
+    pub poem_data: PoemLineRecord,
+    pub player_ids: Vec<String>, // 参加加赛的玩家，其他玩家等待结果
+    pub remaining_time: i64,     // ms, 本轮的答题时间，同步答题时包含等待上一首结果显示的时间
+}
+
+impl GCProtoBase64 for GCTiebreak {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCTiebreak: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
+// 同步答题，所有玩家一起开始回答 opt_index 这首诗
+// 时间戳都是服务器时间，客户端用 server_timestamp 和本地时间的差值换算
+#[derive(Serialize)]
+pub struct GCNextQuestion {
+    pub game_id: String,
+    pub opt_index: u32,
+    pub server_timestamp: i64,  // 发送消息时的服务器时间，ms
+    pub start_timestamp: i64,   // 开始作答的时间，前一首诗的结果显示完之后
+    pub timeout_timestamp: i64, // 超过该时间没有作答按答错处理
+}
+
+impl GCProtoBase64 for GCNextQuestion {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCNextQuestion: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
+// 断线重连后，请求恢复进行中的游戏
+#[derive(Deserialize, Debug)]
+pub struct CGResumeGame {
+    pub id: String,      // 玩家ID
+    pub game_id: String, // 游戏ID
+    #[serde(default)]
+    pub token: String, // 会话令牌，重连后需要重新校验
+}
+
+// 恢复游戏的完整快照，code 为 0 时有效，-1 表示游戏不存在或已结束，-2 表示玩家已经判负
+#[derive(Serialize, Default)]
+pub struct GCResumeGame {
+    pub code: i32,
+    pub game_id: String,
+    pub player1_id: String,
+    pub player1_name: String,
+    pub player1_next_opt_index: i32,
+    pub player1_opt_bitmap: u32, // 老客户端使用，只有前 32 题，新客户端使用 players 中的 opt_results
+    pub player2_id: String,
+    pub player2_name: String,
+    pub player2_next_opt_index: i32,
+    pub player2_opt_bitmap: u32,
+    pub players: Vec<GamePlayerProgress>,
+    pub poem_data: Vec<PoemLineRecord>,
+    pub remaining_time: i64, // 重连玩家当前这首诗剩余的答题时间，ms
+    pub tiebreak_round: u32, // 加赛轮数，加赛的诗词追加在 poem_data 后面
+    pub poem_num: u32,
+    pub poem_mill_time: i64,
+    pub poem_result_wait: i64,
+    pub lockstep: bool, // 同步答题时 remaining_time 为 0 表示等待下一首开始
+}
+
+impl GCProtoBase64 for GCResumeGame {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCResumeGame: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
+// 连接建立后客户端发送的第一条消息，老版本客户端不发送，按 json 编码处理
+#[derive(Deserialize, Debug)]
+pub struct CGHello {
+    pub version: u32,           // 客户端版本号
+    pub area: String,           // 客户端地区，zh、en
+    pub encodings: Vec<String>, // 客户端支持的编码，json、binary
+}
+
+// 0 成功，-1 客户端版本过低，-2 地区不匹配
+#[derive(Serialize)]
+pub struct GCHello {
+    pub code: i32,
+    pub encoding: String,        // 之后的消息使用的编码
+    pub min_client_version: u32, // 服务器支持的最低客户端版本
+}
+
+impl GCProtoBase64 for GCHello {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCHello: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
+#[derive(Serialize)]
+pub struct GCError {
+    pub code: i32,     // ErrorCode
+    pub proto_id: u64, // 出错的客户端协议ID，外层解析失败时为 0
+}
+
+impl GCProtoBase64 for GCError {
+    fn to_base64_json_str(&self) -> Option<String> {
+        if let Ok(json_str) = serde_json::to_string(self) {
+            log::info!("GCError: {:?}", json_str);
+            let base64_json_str = base64::encode(json_str);
+            return Some(base64_json_str);
+        }
+        return None;
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ProtoData {
     pub proto_id: u64,
@@ -138,44 +586,188 @@ pub struct ProtoData {
 }
 
 impl ProtoData {
-    pub fn gc_to_json_string(proto_id: u64, proto: impl GCProtoBase64) -> Option<String> {
-        if let Some(proto_json_str) = proto.to_base64_json_str() {
-            let proto_data = Self {
-                proto_id: proto_id,
-                proto_json_str: proto_json_str,
-            };
-
-            if let Ok(proto_json_str) = serde_json::to_string(&proto_data) {
-                return Some(proto_json_str);
-            }
+    pub fn gc_to_message(proto_id: u64, proto: impl GCProto + 'static) -> GCMessage {
+        return GCMessage {
+            proto_id,
+            proto: Box::new(proto),
+            json_str: None,
+            binary_frame: None,
+        };
+    }
+
+    pub fn gc_error_to_message(proto_id: u64, error_code: ErrorCode) -> GCMessage {
+        return Self::gc_to_message(
+            PROTO_GCERROR,
+            GCError {
+                code: error_code as i32,
+                proto_id,
+            },
+        );
+    }
+
+    // json 文本不会以合法的长度前缀开头，据此区分两种编码
+    pub fn is_binary_frame(data: &[u8]) -> bool {
+        if data.len() < BINARY_FRAME_HEADER_LEN {
+            return false;
         }
+        let frame_len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        return frame_len == data.len() - 4;
+    }
 
-        return None;
+    // 二进制帧转成 json 外层格式，游戏逻辑只处理一种格式
+    pub fn binary_to_json_string(data: &[u8]) -> Result<String, ErrorCode> {
+        if !Self::is_binary_frame(data) {
+            return Err(ErrorCode::BadFrame);
+        }
+        let mut proto_id_bytes = [0u8; 8];
+        proto_id_bytes.copy_from_slice(&data[4..BINARY_FRAME_HEADER_LEN]);
+        let proto_id = u64::from_be_bytes(proto_id_bytes);
+
+        let mut payload = &data[BINARY_FRAME_HEADER_LEN..];
+        let value = rmp_serde::from_read::<_, serde_json::Value>(&mut payload)
+            .map_err(|_| ErrorCode::BadFrame)?;
+        // 协议都是结构体，数据必须正好是一个 map，不能有多余的字节
+        if !value.is_object() || !payload.is_empty() {
+            return Err(ErrorCode::BadFrame);
+        }
+        let json_str = serde_json::to_string(&value).map_err(|_| ErrorCode::BadFrame)?;
+        let proto_data = Self {
+            proto_id,
+            proto_json_str: base64::encode(json_str),
+        };
+        return serde_json::to_string(&proto_data).map_err(|_| ErrorCode::BadFrame);
+    }
+
+    // 发给 json 连接的消息
+    fn gc_to_json_string(proto_id: u64, proto: &dyn GCProto) -> Option<String> {
+        let proto_data = Self {
+            proto_id,
+            proto_json_str: proto.to_base64_json_str()?,
+        };
+        return serde_json::to_string(&proto_data).ok();
+    }
+
+    // 发给二进制连接的消息，协议直接编码成 MessagePack
+    fn gc_to_binary(proto_id: u64, proto: &dyn GCProto) -> Option<Vec<u8>> {
+        let payload = proto.to_msgpack()?;
+
+        let frame_len = (8 + payload.len()) as u32;
+        let mut frame = Vec::with_capacity(4 + frame_len as usize);
+        frame.extend_from_slice(&frame_len.to_be_bytes());
+        frame.extend_from_slice(&proto_id.to_be_bytes());
+        frame.extend_from_slice(&payload);
+        return Some(frame);
     }
 
     // 收到客户端发来的数据，解析出 protoId和具体协议的 base64_json_str
-    pub fn cg_to_proto_json_str(json_str: String) -> Option<(u64, String)> {
+    pub fn cg_to_proto_json_str(json_str: String) -> Result<(u64, String), ErrorCode> {
         if let Ok(proto_data) = serde_json::from_str::<ProtoData>(&json_str) {
-            return Some((proto_data.proto_id, proto_data.proto_json_str));
+            return Ok((proto_data.proto_id, proto_data.proto_json_str));
         }
-        return None;
+        return Err(ErrorCode::BadEnvelope);
     }
 
     // 将 Base64 json str 转成具体的协议
-    pub fn deserialize_proto<T>(base64_json_str: String) -> Option<T>
+    pub fn deserialize_proto<T>(base64_json_str: String) -> Result<T, ErrorCode>
     where
         T: serde::de::DeserializeOwned,
     {
-        if let Ok(bytes) = base64::decode(base64_json_str) {
-            if let Ok(raw_json_str) = str::from_utf8(&bytes) {
-                log::info!("Client -> Server JsonStr: {:?}", raw_json_str);
-                if let Ok(proto) = serde_json::from_str::<T>(raw_json_str) {
-                    return Some(proto);
-                } else {
-                    log::info!("Error!, Deserialize json to CG proto failed!");
-                }
-            }
+        let bytes = base64::decode(base64_json_str).map_err(|_| ErrorCode::BadBase64)?;
+        let raw_json_str = str::from_utf8(&bytes).map_err(|_| ErrorCode::BadJson)?;
+        log::info!("Client -> Server JsonStr: {:?}", raw_json_str);
+        if let Ok(proto) = serde_json::from_str::<T>(raw_json_str) {
+            return Ok(proto);
+        } else {
+            log::info!("Error!, Deserialize json to CG proto failed!");
         }
-        return None;
+        return Err(ErrorCode::BadJson);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_message() -> GCMessage {
+        ProtoData::gc_error_to_message(PROTO_CGSTARTMATCH, ErrorCode::StaleOptIndex)
+    }
+
+    // json 外层格式中的协议数据
+    fn proto_value(json_str: &str) -> (u64, serde_json::Value) {
+        let proto_data = serde_json::from_str::<ProtoData>(json_str).unwrap();
+        let bytes = base64::decode(proto_data.proto_json_str).unwrap();
+        return (proto_data.proto_id, serde_json::from_slice(&bytes).unwrap());
+    }
+
+    fn binary_frame(proto_id: u64, payload: &[u8]) -> Vec<u8> {
+        let mut frame = ((8 + payload.len()) as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&proto_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        return frame;
+    }
+
+    #[test]
+    fn binary_frame_round_trip() {
+        let mut gc_message = error_message();
+        let binary_frame = gc_message.bytes(Encoding::Binary).unwrap().to_vec();
+        assert!(ProtoData::is_binary_frame(&binary_frame));
+
+        let json_str = ProtoData::binary_to_json_string(&binary_frame).unwrap();
+        let expected_json_str = str::from_utf8(gc_message.bytes(Encoding::Json).unwrap()).unwrap();
+        assert_eq!(proto_value(&json_str), proto_value(expected_json_str));
+        assert_eq!(
+            proto_value(&json_str),
+            (
+                PROTO_GCERROR,
+                serde_json::json!({"code": 7, "proto_id": PROTO_CGSTARTMATCH})
+            )
+        );
+    }
+
+    #[test]
+    fn encode_only_when_needed() {
+        let mut gc_message = error_message();
+        assert!(gc_message.json_str.is_none() && gc_message.binary_frame.is_none());
+
+        gc_message.bytes(Encoding::Binary).unwrap();
+        assert!(gc_message.json_str.is_none() && gc_message.binary_frame.is_some());
+    }
+
+    #[test]
+    fn json_message_is_not_binary_frame() {
+        let mut gc_message = error_message();
+        let json_bytes = gc_message.bytes(Encoding::Json).unwrap();
+        assert!(!ProtoData::is_binary_frame(json_bytes));
+        assert_eq!(
+            ProtoData::binary_to_json_string(json_bytes),
+            Err(ErrorCode::BadFrame)
+        );
+        assert!(!ProtoData::is_binary_frame(b"{}"));
+    }
+
+    #[test]
+    fn json_payload_with_valid_length_is_rejected() {
+        // 长度前缀正确，但数据是 json 文本而不是 MessagePack
+        let frame = binary_frame(PROTO_CGSTARTMATCH, br#"{"id":"p1","name":"p1"}"#);
+        assert!(ProtoData::is_binary_frame(&frame));
+        assert_eq!(
+            ProtoData::binary_to_json_string(&frame),
+            Err(ErrorCode::BadFrame)
+        );
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut payload = rmp_serde::to_vec_named(&serde_json::json!({"id": "p1"})).unwrap();
+        let frame = binary_frame(PROTO_CGSTARTMATCH, &payload);
+        assert!(ProtoData::binary_to_json_string(&frame).is_ok());
+
+        payload.push(0);
+        let frame = binary_frame(PROTO_CGSTARTMATCH, &payload);
+        assert!(ProtoData::is_binary_frame(&frame));
+        assert_eq!(
+            ProtoData::binary_to_json_string(&frame),
+            Err(ErrorCode::BadFrame)
+        );
     }
 }
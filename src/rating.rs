@@ -0,0 +1,559 @@
+use crate::petable::PETable;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+pub const DEFAULT_DEVIATION: f64 = 350.0;
+pub const DEFAULT_VOLATILITY: f64 = 0.06;
+
+// 服务器保存的玩家分数，存在 Redis 的 hash 中，field 为玩家ID，value 为 json
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayerRating {
+    pub elo_score: i32,
+    pub level: u32,
+    #[serde(default)]
+    pub rated_games: u32, // 计算过分数的对局数，用于判断是否还在定级期
+    #[serde(default = "default_deviation")]
+    pub deviation: f64, // Glicko-2 分数偏差，越大说明分数越不可信
+    #[serde(default = "default_volatility")]
+    pub volatility: f64, // Glicko-2 波动率
+}
+
+fn default_deviation() -> f64 {
+    DEFAULT_DEVIATION
+}
+
+fn default_volatility() -> f64 {
+    DEFAULT_VOLATILITY
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RatingSystemType {
+    Elo,
+    Glicko2,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Glicko2Config {
+    #[serde(deserialize_with = "deserialize_tau")]
+    pub tau: f64, // 限制波动率变化的系统常数，一般 0.3 - 1.2
+    pub min_deviation: f64,       // 分数偏差的下限，避免老玩家分数完全不动
+    pub deviation_per_group: f64, // 分数偏差每超出下限这么多，匹配时多放宽一个分差组
+}
+
+// tau 不大于 0 时求新的波动率无法收敛，加载配置时直接报错
+fn deserialize_tau<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let tau = f64::deserialize(deserializer)?;
+    if tau > 0.0 {
+        return Ok(tau);
+    }
+    return Err(serde::de::Error::custom(format!(
+        "glicko2 tau must be positive, got {}",
+        tau
+    )));
+}
+
+// 分数下限和定级期规则，两种分数系统都适用
+#[derive(Debug, Deserialize, Clone)]
+pub struct RatingRules {
+    pub floor: Option<i32>,        // 分数下限，不配置则允许负分
+    pub provisional_games: u32,    // 前这么多局为定级期
+    pub provisional_k_factor: f64, // 定级期使用的 K 值，新玩家更快收敛到真实水平
+}
+
+impl RatingRules {
+    pub fn is_provisional(&self, rating: &PlayerRating) -> bool {
+        rating.rated_games < self.provisional_games
+    }
+
+    // 四舍五入后限制在下限之上
+    pub fn apply_floor(&self, elo_score: f64) -> i32 {
+        let elo_score = elo_score.round() as i32;
+        match self.floor {
+            Some(floor) => elo_score.max(floor),
+            None => elo_score,
+        }
+    }
+}
+
+// 按胜负差距计算实际得分，赢得越多实际得分越接近 1
+#[derive(Debug, Deserialize, Clone)]
+pub struct MarginConfig {
+    pub enabled: bool,
+    pub min_win_score: f64,  // 险胜时胜者的实际得分，负者为 1 - min_win_score
+    pub max_score_diff: f64, // 得分差达到该值时，得分差部分视为大胜
+    pub score_weight: f64,   // 得分差在胜负差距中的权重
+    pub correct_weight: f64, // 答对题数差在胜负差距中的权重
+}
+
+impl MarginConfig {
+    // result: 1 胜，0 负，0.5 平；score_diff 和 correct_diff 为胜负双方的差值
+    pub fn actual_scores(
+        &self,
+        result: (f64, f64),
+        score_diff: u32,
+        correct_diff: u32,
+        poem_num: u32,
+    ) -> (f64, f64) {
+        let (result1, result2) = result;
+        if !self.enabled || result1 + result2 != 1.0 || result1 == result2 {
+            return result;
+        }
+
+        let score_margin = if self.max_score_diff > 0.0 {
+            (score_diff as f64 / self.max_score_diff).min(1.0)
+        } else {
+            1.0
+        };
+        let correct_margin = if poem_num > 0 {
+            (correct_diff as f64 / poem_num as f64).min(1.0)
+        } else {
+            0.0
+        };
+        let margin = (self.score_weight * score_margin + self.correct_weight * correct_margin)
+            .clamp(0.0, 1.0);
+        let win_score = self.min_win_score + (1.0 - self.min_win_score) * margin;
+
+        if result1 > result2 {
+            return (win_score, 1.0 - win_score);
+        } else {
+            return (1.0 - win_score, win_score);
+        }
+    }
+}
+
+pub trait RatingSystem {
+    // score: 玩家的实际得分，1 胜，0 负，0.5 平，开启胜负差距时为 0 - 1 之间的值
+    fn update(
+        &self,
+        rating1: &PlayerRating,
+        rating2: &PlayerRating,
+        score1: f64,
+        score2: f64,
+    ) -> (PlayerRating, PlayerRating);
+
+    // 分数不确定的玩家，匹配时可以额外放宽的分差组数
+    fn search_group_bonus(&self, _deviation: f64) -> u32 {
+        0
+    }
+}
+
+// 队伍的分数取队员的平均值，用于和对手队伍的玩家计算
+fn team_rating(ratings: &[&PlayerRating]) -> PlayerRating {
+    let num = ratings.len().max(1) as f64;
+    PlayerRating {
+        elo_score: (ratings
+            .iter()
+            .map(|rating| rating.elo_score as f64)
+            .sum::<f64>()
+            / num)
+            .round() as i32,
+        level: 0,
+        rated_games: 0,
+        deviation: ratings.iter().map(|rating| rating.deviation).sum::<f64>() / num,
+        volatility: ratings.iter().map(|rating| rating.volatility).sum::<f64>() / num,
+    }
+}
+
+// 多人和组队对局，每个玩家和每个对手队伍按 1v1 计算一次，新的分数取和每个对手队伍计算结果的平均值
+// 每个玩家单独一队时就是两两计算
+// player_teams: 每个玩家所在的队伍，team_pair_scores: (队伍 i, 队伍 j, i 的实际得分, j 的实际得分)
+pub fn update_teams(
+    rating_system: &dyn RatingSystem,
+    ratings: &[PlayerRating],
+    player_teams: &[usize],
+    team_pair_scores: &[(usize, usize, f64, f64)],
+) -> Vec<PlayerRating> {
+    let team_num = player_teams.iter().max().map_or(0, |team| team + 1);
+    let team_rating_vec: Vec<PlayerRating> = (0..team_num)
+        .map(|team| {
+            let member_rating_vec: Vec<&PlayerRating> = ratings
+                .iter()
+                .zip(player_teams.iter())
+                .filter(|(_, player_team)| **player_team == team)
+                .map(|(rating, _)| rating)
+                .collect();
+            team_rating(&member_rating_vec)
+        })
+        .collect();
+
+    return ratings
+        .iter()
+        .zip(player_teams.iter())
+        .map(|(rating, &team)| {
+            let mut sum = (0.0, 0.0, 0.0, 0);
+            for &(team_i, team_j, score_i, score_j) in team_pair_scores.iter() {
+                let (opponent_team, score, opponent_score) = if team_i == team {
+                    (team_j, score_i, score_j)
+                } else if team_j == team {
+                    (team_i, score_j, score_i)
+                } else {
+                    continue;
+                };
+                let (new_rating, _) = rating_system.update(
+                    rating,
+                    &team_rating_vec[opponent_team],
+                    score,
+                    opponent_score,
+                );
+                sum.0 += new_rating.elo_score as f64;
+                sum.1 += new_rating.deviation;
+                sum.2 += new_rating.volatility;
+                sum.3 += 1;
+            }
+
+            // 每个结果都不低于分数下限，平均值也不会低于
+            let mut new_rating = rating.clone();
+            if sum.3 > 0 {
+                new_rating.elo_score = (sum.0 / sum.3 as f64).round() as i32;
+                new_rating.deviation = sum.1 / sum.3 as f64;
+                new_rating.volatility = sum.2 / sum.3 as f64;
+            }
+            new_rating
+        })
+        .collect();
+}
+
+pub fn create_rating_system(
+    rating_system_type: RatingSystemType,
+    elo_k_factor: f64,
+    glicko2_config: &Glicko2Config,
+    rules: &RatingRules,
+) -> Box<dyn RatingSystem> {
+    match rating_system_type {
+        RatingSystemType::Elo => Box::new(TableElo {
+            petable: PETable::new(),
+            k_factor: elo_k_factor,
+            rules: rules.clone(),
+        }),
+        RatingSystemType::Glicko2 => Box::new(Glicko2 {
+            config: glicko2_config.clone(),
+            rules: rules.clone(),
+        }),
+    }
+}
+
+// 按 pet.csv 中的分差查期望胜率的 elo
+pub struct TableElo {
+    petable: PETable,
+    k_factor: f64,
+    rules: RatingRules,
+}
+
+impl TableElo {
+    fn k_factor(&self, rating: &PlayerRating) -> f64 {
+        if self.rules.is_provisional(rating) {
+            self.rules.provisional_k_factor
+        } else {
+            self.k_factor
+        }
+    }
+}
+
+impl RatingSystem for TableElo {
+    fn update(
+        &self,
+        rating1: &PlayerRating,
+        rating2: &PlayerRating,
+        score1: f64,
+        score2: f64,
+    ) -> (PlayerRating, PlayerRating) {
+        let (ea, eb, _) = self.petable.get_ea_eb(rating1.elo_score, rating2.elo_score);
+        log::info!(
+            "ea = {}, eb = {}, player1_sa = {}, player2_sa = {}",
+            ea,
+            eb,
+            score1,
+            score2,
+        );
+
+        let mut new_rating1 = rating1.clone();
+        let mut new_rating2 = rating2.clone();
+        new_rating1.elo_score = self
+            .rules
+            .apply_floor(rating1.elo_score as f64 + self.k_factor(rating1) * (score1 - ea));
+        new_rating2.elo_score = self
+            .rules
+            .apply_floor(rating2.elo_score as f64 + self.k_factor(rating2) * (score2 - eb));
+        return (new_rating1, new_rating2);
+    }
+}
+
+// Glicko-2，每局游戏作为一个评分周期
+// http://www.glicko.net/glicko/glicko2.pdf
+pub struct Glicko2 {
+    config: Glicko2Config,
+    rules: RatingRules,
+}
+
+const GLICKO2_SCALE: f64 = 173.7178;
+const GLICKO2_EPSILON: f64 = 0.000001;
+// 求新的波动率时的最大迭代次数，正常情况下几十次以内就会收敛
+const GLICKO2_MAX_ITERATIONS: u32 = 100;
+
+impl Glicko2 {
+    fn g(phi: f64) -> f64 {
+        1.0 / (1.0 + 3.0 * phi * phi / (PI * PI)).sqrt()
+    }
+
+    fn update_one(
+        &self,
+        rating: &PlayerRating,
+        opponent: &PlayerRating,
+        score: f64,
+    ) -> PlayerRating {
+        return self.update_results(rating, &[(opponent, score)]);
+    }
+
+    // 一个评分周期内和多个对手的结果，results 为 (对手, 实际得分)
+    fn update_results(
+        &self,
+        rating: &PlayerRating,
+        results: &[(&PlayerRating, f64)],
+    ) -> PlayerRating {
+        // 分数只用到差值，不需要减去 1500 的中心值
+        let mu = rating.elo_score as f64 / GLICKO2_SCALE;
+        let phi = rating.deviation / GLICKO2_SCALE;
+        let sigma = rating.volatility;
+
+        // g(phi_j), E(mu, mu_j, phi_j), s_j
+        let result_vec: Vec<(f64, f64, f64)> = results
+            .iter()
+            .map(|(opponent, score)| {
+                let mu_j = opponent.elo_score as f64 / GLICKO2_SCALE;
+                let g_j = Self::g(opponent.deviation / GLICKO2_SCALE);
+                let e_j = 1.0 / (1.0 + (-g_j * (mu - mu_j)).exp());
+                (g_j, e_j, *score)
+            })
+            .collect();
+        let v = 1.0
+            / result_vec
+                .iter()
+                .map(|(g_j, e_j, _)| g_j * g_j * e_j * (1.0 - e_j))
+                .sum::<f64>();
+        let improvement: f64 = result_vec
+            .iter()
+            .map(|(g_j, e_j, score)| g_j * (score - e_j))
+            .sum();
+        let delta = v * improvement;
+
+        // 迭代求新的波动率 (Illinois algorithm)
+        let tau = self.config.tau;
+        let a = (sigma * sigma).ln();
+        let f = |x: f64| {
+            let ex = x.exp();
+            let d = phi * phi + v + ex;
+            ex * (delta * delta - phi * phi - v - ex) / (2.0 * d * d) - (x - a) / (tau * tau)
+        };
+        let mut big_a = a;
+        let mut big_b = if delta * delta > phi * phi + v {
+            (delta * delta - phi * phi - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * tau) < 0.0 && k < GLICKO2_MAX_ITERATIONS as f64 {
+                k += 1.0;
+            }
+            a - k * tau
+        };
+        let mut f_a = f(big_a);
+        let mut f_b = f(big_b);
+        let mut iterations = 0;
+        while (big_b - big_a).abs() > GLICKO2_EPSILON {
+            iterations += 1;
+            if iterations > GLICKO2_MAX_ITERATIONS {
+                log::warn!(
+                    "glicko2 volatility not converged, rating: {:?}, tau: {}",
+                    rating,
+                    tau
+                );
+                break;
+            }
+            let big_c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+            let f_c = f(big_c);
+            if f_c * f_b <= 0.0 {
+                big_a = big_b;
+                f_a = f_b;
+            } else {
+                f_a /= 2.0;
+            }
+            big_b = big_c;
+            f_b = f_c;
+        }
+        let new_sigma = (big_a / 2.0).exp();
+
+        let phi_star = (phi * phi + new_sigma * new_sigma).sqrt();
+        let new_phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let new_mu = mu + new_phi * new_phi * improvement;
+
+        let new_deviation = (new_phi * GLICKO2_SCALE)
+            .max(self.config.min_deviation)
+            .min(DEFAULT_DEVIATION);
+
+        let mut new_rating = rating.clone();
+        new_rating.elo_score = self.rules.apply_floor(new_mu * GLICKO2_SCALE);
+        new_rating.deviation = new_deviation;
+        new_rating.volatility = new_sigma;
+        return new_rating;
+    }
+}
+
+impl RatingSystem for Glicko2 {
+    fn update(
+        &self,
+        rating1: &PlayerRating,
+        rating2: &PlayerRating,
+        score1: f64,
+        score2: f64,
+    ) -> (PlayerRating, PlayerRating) {
+        let new_rating1 = self.update_one(rating1, rating2, score1);
+        let new_rating2 = self.update_one(rating2, rating1, score2);
+        log::info!(
+            "glicko2: {:?} -> {:?}, {:?} -> {:?}",
+            rating1,
+            new_rating1,
+            rating2,
+            new_rating2
+        );
+        return (new_rating1, new_rating2);
+    }
+
+    fn search_group_bonus(&self, deviation: f64) -> u32 {
+        if deviation <= self.config.min_deviation || self.config.deviation_per_group <= 0.0 {
+            return 0;
+        }
+        return ((deviation - self.config.min_deviation) / self.config.deviation_per_group) as u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(elo_score: i32, deviation: f64) -> PlayerRating {
+        PlayerRating {
+            elo_score,
+            level: 0,
+            rated_games: 0,
+            deviation,
+            volatility: DEFAULT_VOLATILITY,
+        }
+    }
+
+    fn glicko2(tau: f64) -> Glicko2 {
+        Glicko2 {
+            config: Glicko2Config {
+                tau,
+                min_deviation: 0.0,
+                deviation_per_group: 100.0,
+            },
+            rules: RatingRules {
+                floor: None,
+                provisional_games: 0,
+                provisional_k_factor: 32.0,
+            },
+        }
+    }
+
+    // http://www.glicko.net/glicko/glicko2.pdf 中的例子
+    #[test]
+    fn glicko2_worked_example() {
+        let player = rating(1500, 200.0);
+        let (opponent1, opponent2, opponent3) =
+            (rating(1400, 30.0), rating(1550, 100.0), rating(1700, 300.0));
+        let new_rating = glicko2(0.5).update_results(
+            &player,
+            &[(&opponent1, 1.0), (&opponent2, 0.0), (&opponent3, 0.0)],
+        );
+
+        assert_eq!(new_rating.elo_score, 1464);
+        assert!((new_rating.deviation - 151.52).abs() < 0.01);
+        assert!((new_rating.volatility - 0.05999).abs() < 0.00001);
+    }
+
+    #[test]
+    fn glicko2_tiny_tau_terminates() {
+        let player = rating(1500, 350.0);
+        let opponent = rating(3000, 30.0);
+        let new_rating = glicko2(0.000001).update_one(&player, &opponent, 1.0);
+        assert!(new_rating.volatility.is_finite());
+        assert!(new_rating.elo_score > player.elo_score);
+    }
+
+    #[test]
+    fn non_positive_tau_is_rejected() {
+        let config_json = |tau: f64| {
+            format!(
+                r#"{{"tau": {}, "min_deviation": 50.0, "deviation_per_group": 100.0}}"#,
+                tau
+            )
+        };
+        assert!(serde_json::from_str::<Glicko2Config>(&config_json(0.5)).is_ok());
+        assert!(serde_json::from_str::<Glicko2Config>(&config_json(0.0)).is_err());
+        assert!(serde_json::from_str::<Glicko2Config>(&config_json(-0.5)).is_err());
+    }
+
+    fn margin_config() -> MarginConfig {
+        MarginConfig {
+            enabled: true,
+            min_win_score: 0.75,
+            max_score_diff: 5000.0,
+            score_weight: 0.7,
+            correct_weight: 0.3,
+        }
+    }
+
+    #[test]
+    fn margin_draw_is_half() {
+        let margin_config = margin_config();
+        assert_eq!(
+            margin_config.actual_scores((0.5, 0.5), 0, 0, 10),
+            (0.5, 0.5)
+        );
+        assert_eq!(
+            margin_config.actual_scores((0.5, 0.5), 3000, 4, 10),
+            (0.5, 0.5)
+        );
+    }
+
+    #[test]
+    fn margin_scores_are_symmetric() {
+        let margin_config = margin_config();
+        for (score_diff, correct_diff) in [(0, 0), (1200, 2), (5000, 10), (9000, 12)] {
+            let (win1, lose2) =
+                margin_config.actual_scores((1.0, 0.0), score_diff, correct_diff, 10);
+            let (lose1, win2) =
+                margin_config.actual_scores((0.0, 1.0), score_diff, correct_diff, 10);
+            assert_eq!((win1, lose2), (win2, lose1));
+            assert!((win1 + lose2 - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn margin_scores_in_range() {
+        let margin_config = margin_config();
+        let mut last_win_score = 0.0;
+        for score_diff in [0, 100, 2500, 5000, 100000] {
+            for poem_num in [0, 1, 10] {
+                for correct_diff in [0, 1, 10, 20] {
+                    let (win_score, lose_score) =
+                        margin_config.actual_scores((1.0, 0.0), score_diff, correct_diff, poem_num);
+                    assert!((0.0..=1.0).contains(&win_score));
+                    assert!((0.0..=1.0).contains(&lose_score));
+                    // 险胜时至少得 min_win_score
+                    assert!(win_score >= margin_config.min_win_score);
+                }
+            }
+            // 得分差越大，胜者的实际得分越高
+            let (win_score, _) = margin_config.actual_scores((1.0, 0.0), score_diff, 0, 10);
+            assert!(win_score >= last_win_score);
+            last_win_score = win_score;
+        }
+        assert_eq!(
+            margin_config.actual_scores((1.0, 0.0), 100000, 20, 10),
+            (1.0, 0.0)
+        );
+    }
+}
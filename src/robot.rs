@@ -9,7 +9,7 @@ pub struct Robot {
     pub id: String,
     pub name: String,
     pub level: u32,
-    pub elo_score: u32,
+    pub elo_score: i32,
     pub correct_rate: f64,
     pub next_early_opt_time: i64,
 }
@@ -20,7 +20,9 @@ impl Robot {
         // let four_of_time = max_time / 4;
         let mut rng = rand::thread_rng();
         // self.next_early_opt_time = rng.gen_range(1..half_time);
-        self.next_early_opt_time = rng.gen_range(4000..8000);
+        // 10s 答题时间时为 4 - 8s，玩法的答题时间不同时按比例
+        let min_time = max_time * 2 / 5;
+        self.next_early_opt_time = rng.gen_range(min_time..(max_time * 4 / 5).max(min_time + 1));
         // log::error!("Net OptTIME: {}", self.next_early_opt_time);
     }
 
@@ -40,31 +42,21 @@ pub struct RobotController {
 }
 
 impl RobotController {
-    pub fn new() -> Self {
+    pub fn new(robot_table_path: &str) -> Self {
         Self {
-            robottable: RobotTable::new(),
+            robottable: RobotTable::new(robot_table_path),
         }
     }
 
     pub fn get_robot(
         &mut self,
         competitor_level: u32,
-        competitor_elo_score: u32,
+        competitor_elo_score: i32,
         competitor_correct_rate: f64,
         max_opt_wait_time: i64,
     ) -> Robot {
-        let relative_elo_score = competitor_elo_score as i32;
         let mut rng = rand::thread_rng();
         let score_offset: i32 = rng.gen_range(-10..11);
-        let my_score = if score_offset >= 0 {
-            relative_elo_score + score_offset
-        } else {
-            if relative_elo_score - score_offset >= 0 {
-                relative_elo_score - score_offset
-            } else {
-                relative_elo_score
-            }
-        };
 
         let (id, name) = self.robottable.get_id_name();
 
@@ -72,7 +64,7 @@ impl RobotController {
             id,
             name,
             level: competitor_level,
-            elo_score: my_score as u32,
+            elo_score: competitor_elo_score + score_offset,
             correct_rate: rng.gen_range(MIN_CORRECTION_PERCENT..=MAX_CORRECTION_PERCENT),
             // correct_rate: if competitor_correct_rate == 0.0 {
             //     MIN_CORRECTION_PERCENT
@@ -16,8 +16,8 @@ pub struct RobotTable {
 }
 
 impl RobotTable {
-    pub fn new() -> Self {
-        let file = std::fs::File::open("./configs/robot_info.csv").unwrap();
+    pub fn new(path: &str) -> Self {
+        let file = std::fs::File::open(path).unwrap();
         let mut rdr = csv::Reader::from_reader(file);
 
         let mut robot_deque_array: [VecDeque<RobotRecord>; 4] = [
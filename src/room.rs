@@ -0,0 +1,520 @@
+use crate::gamematch::MatchRequest;
+use crate::rating::PlayerRating;
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// 去掉了容易看错的 0 O 1 I
+const ROOM_CODE_CHARS: &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+// 房间号太短时很容易被猜中，也容易用完
+const MIN_ROOM_CODE_LENGTH: usize = 4;
+// 随机生成的房间号都被占用时重试的次数
+const GEN_ROOM_CODE_RETRY: u32 = 100;
+
+// GCCreateRoom.code
+pub const CREATE_ROOM_NO_CODE: i32 = -3; // 没有可用的房间号
+
+// GCJoinRoom.code
+pub const JOIN_ROOM_NOT_FOUND: i32 = -1; // 房间不存在或已关闭
+pub const JOIN_ROOM_FULL: i32 = -2; // 已经有人加入了
+pub const JOIN_ROOM_OWN: i32 = -3; // 不能加入自己创建的房间
+
+// GCRoomClosed.reason
+pub const ROOM_CLOSE_EXPIRED: i32 = 1; // 超时没有人加入
+pub const ROOM_CLOSE_CANCELED: i32 = 2; // 房间里的玩家取消了
+pub const ROOM_CLOSE_DISCONNECTED: i32 = 3; // 房间里的玩家断线了
+pub const ROOM_CLOSE_RATING_FAILED: i32 = 4; // 读取玩家分数失败
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RoomConfig {
+    #[serde(deserialize_with = "deserialize_code_length")]
+    pub code_length: usize,
+    pub expire_time: i64,  // ms, 房间创建后超过该时间还没有开始游戏，自动关闭
+    pub allow_rated: bool, // 是否允许好友对战改变分数和等级
+}
+
+fn deserialize_code_length<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let code_length = usize::deserialize(deserializer)?;
+    if code_length >= MIN_ROOM_CODE_LENGTH {
+        return Ok(code_length);
+    }
+    return Err(serde::de::Error::custom(format!(
+        "room code_length must be at least {}, got {}",
+        MIN_ROOM_CODE_LENGTH, code_length
+    )));
+}
+
+// 房间里的玩家，分数从 Redis 读取完成后才能开始游戏
+#[derive(Debug)]
+struct RoomPlayer {
+    match_request: MatchRequest,
+    is_rating_loaded: bool,
+}
+
+impl RoomPlayer {
+    fn new(match_request: MatchRequest) -> Self {
+        Self {
+            match_request,
+            is_rating_loaded: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Room {
+    owner: RoomPlayer,
+    guest: Option<RoomPlayer>,
+    is_rated: bool,
+    create_timestamp: i64,
+}
+
+impl Room {
+    fn players(&self) -> Vec<&RoomPlayer> {
+        let mut room_player_vec = vec![&self.owner];
+        if let Some(ref guest) = self.guest {
+            room_player_vec.push(guest);
+        }
+        return room_player_vec;
+    }
+
+    fn player_mut(&mut self, player_id: &str) -> Option<&mut RoomPlayer> {
+        if self.owner.match_request.player_id == player_id {
+            return Some(&mut self.owner);
+        }
+        return self
+            .guest
+            .as_mut()
+            .filter(|guest| guest.match_request.player_id == player_id);
+    }
+
+    fn is_ready(&self) -> bool {
+        match self.guest {
+            Some(ref guest) => self.owner.is_rating_loaded && guest.is_rating_loaded,
+            None => false,
+        }
+    }
+
+    fn into_match_requests(self) -> Vec<MatchRequest> {
+        let mut match_request_vec = vec![self.owner.match_request];
+        if let Some(guest) = self.guest {
+            match_request_vec.push(guest.match_request);
+        }
+        return match_request_vec;
+    }
+}
+
+// 两个玩家的分数都读取完成，可以开始游戏的房间
+pub struct ReadyRoom {
+    pub owner_request: MatchRequest,
+    pub guest_request: MatchRequest,
+    pub is_rated: bool,
+}
+
+// 关闭的房间号和房间里的玩家
+pub type ClosedRoom = (String, Vec<MatchRequest>);
+
+// 好友对战，一个玩家创建房间拿到房间号，另一个玩家用房间号加入，不经过匹配队列
+pub struct RoomController {
+    config: RoomConfig,
+    room_map: HashMap<String, Room>,
+    // 玩家ID - 所在的房间号
+    player_room_map: HashMap<String, String>,
+}
+
+impl RoomController {
+    pub fn new(config: RoomConfig) -> Self {
+        Self {
+            config,
+            room_map: HashMap::new(),
+            player_room_map: HashMap::new(),
+        }
+    }
+
+    pub fn expire_time(&self) -> i64 {
+        self.config.expire_time
+    }
+
+    // 重试多次都和已有的房间重复时返回 None
+    fn gen_room_code(&self) -> Option<String> {
+        let mut rng = rand::thread_rng();
+        for _ in 0..GEN_ROOM_CODE_RETRY {
+            let room_code: String = (0..self.config.code_length)
+                .map(|_| ROOM_CODE_CHARS[rng.gen_range(0..ROOM_CODE_CHARS.len())] as char)
+                .collect();
+            if !self.room_map.contains_key(&room_code) {
+                return Some(room_code);
+            }
+        }
+        return None;
+    }
+
+    // 创建房间，返回房间号，失败时返回 GCCreateRoom.code
+    pub fn create_room(
+        &mut self,
+        match_request: MatchRequest,
+        is_rated: bool,
+        curr_timestamp: i64,
+    ) -> Result<String, i32> {
+        let room_code = match self.gen_room_code() {
+            Some(room_code) => room_code,
+            None => {
+                log::error!(
+                    "Player {} create room failed, no room code available, rooms: {}",
+                    match_request.player_id,
+                    self.room_map.len()
+                );
+                return Err(CREATE_ROOM_NO_CODE);
+            }
+        };
+        log::info!(
+            "Player {} create room {}, rated: {}",
+            match_request.player_id,
+            room_code,
+            is_rated
+        );
+        self.player_room_map
+            .insert(match_request.player_id.clone(), room_code.clone());
+        self.room_map.insert(
+            room_code.clone(),
+            Room {
+                owner: RoomPlayer::new(match_request),
+                guest: None,
+                is_rated: is_rated && self.config.allow_rated,
+                create_timestamp: curr_timestamp,
+            },
+        );
+        return Ok(room_code);
+    }
+
+    // 加入的玩家使用房主所在的队列，返回队列ID
+    pub fn join_room(
+        &mut self,
+        room_code: &str,
+        mut match_request: MatchRequest,
+    ) -> Result<String, i32> {
+        let room = match self.room_map.get_mut(room_code) {
+            Some(room) => room,
+            None => return Err(JOIN_ROOM_NOT_FOUND),
+        };
+        if room.owner.match_request.player_id == match_request.player_id {
+            return Err(JOIN_ROOM_OWN);
+        }
+        if room.guest.is_some() {
+            return Err(JOIN_ROOM_FULL);
+        }
+
+        log::info!("Player {} join room {}", match_request.player_id, room_code);
+        let queue_id = room.owner.match_request.queue_id.clone();
+        match_request.queue_id = queue_id.clone();
+        self.player_room_map
+            .insert(match_request.player_id.clone(), room_code.to_string());
+        room.guest = Some(RoomPlayer::new(match_request));
+        return Ok(queue_id);
+    }
+
+    // 分数读取完成，不是房间里的玩家返回 false
+    pub fn on_rating_loaded(&mut self, player_id: &str, some_rating: Option<PlayerRating>) -> bool {
+        let room = match self.player_room_map.get(player_id) {
+            Some(room_code) => match self.room_map.get_mut(room_code) {
+                Some(room) => room,
+                None => return false,
+            },
+            None => return false,
+        };
+
+        if let Some(room_player) = room.player_mut(player_id) {
+            if let Some(rating) = some_rating {
+                room_player.match_request.apply_rating(rating);
+            }
+            room_player.is_rating_loaded = true;
+        }
+        return true;
+    }
+
+    fn close_room(&mut self, room_code: &str) -> Option<ClosedRoom> {
+        let room = self.room_map.remove(room_code)?;
+        log::info!("Room {} closed", room_code);
+        let match_request_vec = room.into_match_requests();
+        for match_request in match_request_vec.iter() {
+            self.player_room_map.remove(&match_request.player_id);
+        }
+        return Some((room_code.to_string(), match_request_vec));
+    }
+
+    // 房间里任何一个玩家离开，房间都会关闭，返回房间号和房间里的所有玩家
+    pub fn leave_room(&mut self, player_id: &str) -> Option<(String, Vec<MatchRequest>)> {
+        let room_code = self.player_room_map.get(player_id)?.clone();
+        return self.close_room(&room_code);
+    }
+
+    pub fn leave_room_by_endpoint(
+        &mut self,
+        endpoint_id: &str,
+    ) -> Option<(String, Vec<MatchRequest>)> {
+        let some_room_code = self
+            .room_map
+            .iter()
+            .find(|(_, room)| {
+                room.players().iter().any(|room_player| {
+                    room_player.match_request.endpoint_id.as_deref() == Some(endpoint_id)
+                })
+            })
+            .map(|(room_code, _)| room_code.clone());
+        return self.close_room(&some_room_code?);
+    }
+
+    // 返回可以开始游戏的房间（房主，加入的玩家，是否改变分数），以及超时关闭的房间
+    pub fn update_rooms(&mut self, curr_timestamp: i64) -> (Vec<ReadyRoom>, Vec<ClosedRoom>) {
+        let mut ready_vec: Vec<ReadyRoom> = Vec::new();
+        let mut expired_vec: Vec<ClosedRoom> = Vec::new();
+        if self.room_map.is_empty() {
+            return (ready_vec, expired_vec);
+        }
+
+        let ready_code_vec: Vec<String> = self
+            .room_map
+            .iter()
+            .filter(|(_, room)| room.is_ready())
+            .map(|(room_code, _)| room_code.clone())
+            .collect();
+        for room_code in ready_code_vec {
+            if let Some(room) = self.room_map.remove(&room_code) {
+                if let Some(guest) = room.guest {
+                    let owner_request = room.owner.match_request;
+                    let guest_request = guest.match_request;
+                    self.player_room_map.remove(&owner_request.player_id);
+                    self.player_room_map.remove(&guest_request.player_id);
+                    log::info!("Room {} ready, start game!", room_code);
+                    ready_vec.push(ReadyRoom {
+                        owner_request,
+                        guest_request,
+                        is_rated: room.is_rated,
+                    });
+                }
+            }
+        }
+
+        let expire_time = self.config.expire_time;
+        let expired_code_vec: Vec<String> = self
+            .room_map
+            .iter()
+            .filter(|(_, room)| curr_timestamp - room.create_timestamp > expire_time)
+            .map(|(room_code, _)| room_code.clone())
+            .collect();
+        for room_code in expired_code_vec {
+            log::info!("Room {} expired", room_code);
+            if let Some(closed_room) = self.close_room(&room_code) {
+                expired_vec.push(closed_room);
+            }
+        }
+
+        return (ready_vec, expired_vec);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rating;
+
+    const EXPIRE_TIME: i64 = 300000;
+
+    fn room_controller(code_length: usize) -> RoomController {
+        RoomController::new(RoomConfig {
+            code_length,
+            expire_time: EXPIRE_TIME,
+            allow_rated: false,
+        })
+    }
+
+    fn request(player_id: &str, queue_id: &str) -> MatchRequest {
+        MatchRequest {
+            queue_id: queue_id.to_string(),
+            endpoint_id: Some(format!("endpoint_{}", player_id)),
+            player_id: player_id.to_string(),
+            player_name: player_id.to_string(),
+            player_level: 0,
+            player_elo_score: 0,
+            player_rated_games: 0,
+            player_rating_deviation: rating::DEFAULT_DEVIATION,
+            player_rating_volatility: rating::DEFAULT_VOLATILITY,
+            player_correct_rate: 0.0,
+            timestamp: 0,
+        }
+    }
+
+    fn player_ids(match_request_vec: &[MatchRequest]) -> Vec<&str> {
+        match_request_vec
+            .iter()
+            .map(|match_request| match_request.player_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn create_and_join_room() {
+        let mut room_controller = room_controller(6);
+        let room_code = room_controller
+            .create_room(request("owner", "classic_zh"), true, 0)
+            .unwrap();
+        assert_eq!(room_code.len(), 6);
+        assert!(room_code.bytes().all(|c| ROOM_CODE_CHARS.contains(&c)));
+
+        // 加入的玩家使用房主的队列
+        assert_eq!(
+            room_controller.join_room(&room_code, request("guest", "royale_en")),
+            Ok("classic_zh".to_string())
+        );
+        assert!(room_controller.on_rating_loaded("owner", None));
+        let (ready_vec, closed_vec) = room_controller.update_rooms(1000);
+        assert!(ready_vec.is_empty() && closed_vec.is_empty());
+
+        assert!(room_controller.on_rating_loaded("guest", None));
+        assert!(!room_controller.on_rating_loaded("other", None));
+        let (ready_vec, closed_vec) = room_controller.update_rooms(2000);
+        assert!(closed_vec.is_empty());
+        assert_eq!(ready_vec.len(), 1);
+        assert_eq!(ready_vec[0].owner_request.player_id, "owner");
+        assert_eq!(ready_vec[0].guest_request.player_id, "guest");
+        assert_eq!(ready_vec[0].guest_request.queue_id, "classic_zh");
+        // 服务器不允许好友对战改变分数
+        assert!(!ready_vec[0].is_rated);
+
+        // 开始游戏后房间关闭
+        assert_eq!(
+            room_controller.join_room(&room_code, request("late", "classic_zh")),
+            Err(JOIN_ROOM_NOT_FOUND)
+        );
+        assert!(room_controller.leave_room("owner").is_none());
+    }
+
+    #[test]
+    fn join_room_errors() {
+        let mut room_controller = room_controller(6);
+        let room_code = room_controller
+            .create_room(request("owner", "classic_zh"), false, 0)
+            .unwrap();
+
+        assert_eq!(
+            room_controller.join_room("NOROOM", request("guest", "classic_zh")),
+            Err(JOIN_ROOM_NOT_FOUND)
+        );
+        assert_eq!(
+            room_controller.join_room(&room_code, request("owner", "classic_zh")),
+            Err(JOIN_ROOM_OWN)
+        );
+        assert!(room_controller
+            .join_room(&room_code, request("guest", "classic_zh"))
+            .is_ok());
+        assert_eq!(
+            room_controller.join_room(&room_code, request("third", "classic_zh")),
+            Err(JOIN_ROOM_FULL)
+        );
+    }
+
+    #[test]
+    fn leave_room_closes_it_for_everyone() {
+        let mut room_controller = room_controller(6);
+        let room_code = room_controller
+            .create_room(request("owner", "classic_zh"), false, 0)
+            .unwrap();
+        room_controller
+            .join_room(&room_code, request("guest", "classic_zh"))
+            .unwrap();
+
+        let (closed_code, match_request_vec) = room_controller.leave_room("guest").unwrap();
+        assert_eq!(closed_code, room_code);
+        assert_eq!(player_ids(&match_request_vec), ["owner", "guest"]);
+        assert!(room_controller.leave_room("owner").is_none());
+        assert!(!room_controller.on_rating_loaded("owner", None));
+
+        let room_code = room_controller
+            .create_room(request("owner", "classic_zh"), false, 0)
+            .unwrap();
+        let (closed_code, match_request_vec) = room_controller
+            .leave_room_by_endpoint("endpoint_owner")
+            .unwrap();
+        assert_eq!(closed_code, room_code);
+        assert_eq!(player_ids(&match_request_vec), ["owner"]);
+    }
+
+    #[test]
+    fn room_expires_after_expire_time() {
+        let mut room_controller = room_controller(6);
+        let early_code = room_controller
+            .create_room(request("early", "classic_zh"), false, 0)
+            .unwrap();
+        room_controller
+            .create_room(request("late", "classic_zh"), false, 1000)
+            .unwrap();
+
+        let (_, closed_vec) = room_controller.update_rooms(EXPIRE_TIME);
+        assert!(closed_vec.is_empty());
+
+        // 先创建的房间先过期
+        let (_, closed_vec) = room_controller.update_rooms(EXPIRE_TIME + 1);
+        assert_eq!(closed_vec.len(), 1);
+        assert_eq!(closed_vec[0].0, early_code);
+        assert_eq!(player_ids(&closed_vec[0].1), ["early"]);
+
+        let (_, closed_vec) = room_controller.update_rooms(EXPIRE_TIME + 1001);
+        assert_eq!(closed_vec.len(), 1);
+        assert_eq!(player_ids(&closed_vec[0].1), ["late"]);
+    }
+
+    #[test]
+    fn ready_room_starts_before_expire() {
+        let mut room_controller = room_controller(6);
+        let room_code = room_controller
+            .create_room(request("owner", "classic_zh"), false, 0)
+            .unwrap();
+        room_controller
+            .join_room(&room_code, request("guest", "classic_zh"))
+            .unwrap();
+        room_controller.on_rating_loaded("owner", None);
+        room_controller.on_rating_loaded("guest", None);
+
+        // 同一帧里已经可以开始的房间不会被当成过期关闭
+        let (ready_vec, closed_vec) = room_controller.update_rooms(EXPIRE_TIME + 1);
+        assert_eq!(ready_vec.len(), 1);
+        assert!(closed_vec.is_empty());
+    }
+
+    #[test]
+    fn create_room_fails_when_codes_run_out() {
+        let mut room_controller = room_controller(1);
+        for (index, c) in ROOM_CODE_CHARS.iter().enumerate() {
+            let player_id = format!("player{}", index);
+            room_controller.room_map.insert(
+                (*c as char).to_string(),
+                Room {
+                    owner: RoomPlayer::new(request(&player_id, "classic_zh")),
+                    guest: None,
+                    is_rated: false,
+                    create_timestamp: 0,
+                },
+            );
+        }
+
+        assert_eq!(
+            room_controller.create_room(request("owner", "classic_zh"), false, 0),
+            Err(CREATE_ROOM_NO_CODE)
+        );
+        assert!(room_controller.leave_room("owner").is_none());
+    }
+
+    #[test]
+    fn short_code_length_is_rejected() {
+        let config_json = |code_length: usize| {
+            format!(
+                r#"{{"code_length": {}, "expire_time": 300000, "allow_rated": false}}"#,
+                code_length
+            )
+        };
+        assert!(serde_json::from_str::<RoomConfig>(&config_json(4)).is_ok());
+        assert!(serde_json::from_str::<RoomConfig>(&config_json(3)).is_err());
+        assert!(serde_json::from_str::<RoomConfig>(&config_json(0)).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use crate::auth;
+use crate::proto::ErrorCode;
+use std::collections::HashMap;
+
+// 玩家当前所处的状态，不在表中的玩家即为空闲状态
+#[derive(Debug)]
+pub enum PlayerState {
+    Matching(i64),  // 开始匹配的时间戳
+    Gaming(String), // 所在的游戏ID
+    InRoom(String), // 所在的好友房间号
+}
+
+pub struct SessionController {
+    player_state_map: HashMap<String, PlayerState>,
+    // endpoint_id - 令牌校验通过的玩家ID
+    endpoint_player_map: HashMap<String, String>,
+    // 为 None 时不校验玩家身份，兼容老客户端
+    auth_secret: Option<String>,
+}
+
+impl SessionController {
+    pub fn new(auth_secret: Option<String>) -> Self {
+        Self {
+            player_state_map: HashMap::new(),
+            endpoint_player_map: HashMap::new(),
+            auth_secret,
+        }
+    }
+
+    // 校验令牌，通过后将玩家ID绑定到连接上
+    pub fn authenticate(
+        &mut self,
+        endpoint_id: &str,
+        player_id: &str,
+        token: &str,
+        curr_timestamp: i64,
+    ) -> Result<(), ErrorCode> {
+        if let Some(ref auth_secret) = self.auth_secret {
+            if !auth::verify_token(auth_secret, player_id, token, curr_timestamp) {
+                log::warn!(
+                    "Player {} token verify failed, endpoint: {}",
+                    player_id,
+                    endpoint_id
+                );
+                return Err(ErrorCode::Unauthorized);
+            }
+            self.endpoint_player_map
+                .insert(endpoint_id.to_string(), player_id.to_string());
+        }
+        return Ok(());
+    }
+
+    // 连接上后续消息中的玩家ID必须与绑定的一致
+    pub fn check_identity(&self, endpoint_id: &str, player_id: &str) -> Result<(), ErrorCode> {
+        if self.auth_secret.is_some() {
+            match self.endpoint_player_map.get(endpoint_id) {
+                Some(bound_player_id) if bound_player_id == player_id => {}
+                _ => {
+                    log::warn!(
+                        "Player {} is not bound to endpoint {}",
+                        player_id,
+                        endpoint_id
+                    );
+                    return Err(ErrorCode::Unauthorized);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn unbind_endpoint(&mut self, endpoint_id: &str) {
+        self.endpoint_player_map.remove(endpoint_id);
+    }
+
+    pub fn get_state(&self, player_id: &str) -> Option<&PlayerState> {
+        self.player_state_map.get(player_id)
+    }
+
+    pub fn set_matching(&mut self, player_id: &str, start_match_timestamp: i64) {
+        self.player_state_map.insert(
+            player_id.to_string(),
+            PlayerState::Matching(start_match_timestamp),
+        );
+    }
+
+    pub fn set_in_room(&mut self, player_id: &str, room_code: &str) {
+        self.player_state_map.insert(
+            player_id.to_string(),
+            PlayerState::InRoom(room_code.to_string()),
+        );
+    }
+
+    pub fn set_gaming(&mut self, player_id: &str, game_id: &str) {
+        self.player_state_map.insert(
+            player_id.to_string(),
+            PlayerState::Gaming(game_id.to_string()),
+        );
+    }
+
+    pub fn set_idle(&mut self, player_id: &str) {
+        if let Some(state) = self.player_state_map.remove(player_id) {
+            log::info!("Player {} session {:?} -> Idle", player_id, state);
+        }
+    }
+}